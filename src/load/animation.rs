@@ -0,0 +1,421 @@
+use regex::Regex;
+use roxmltree::{Document, Node, NodeId};
+use std::collections::HashMap;
+
+use crate::load::xml::{attribute_value, write_node, SVG_NS, XLINK_NS};
+
+const ANIMATION_ELEMENTS: [&str; 5] = [
+    "animate",
+    "animateColor",
+    "animateMotion",
+    "animateTransform",
+    "set",
+];
+
+enum Repeat {
+    Count(f64),
+    Indefinite,
+}
+
+struct SmilAnimation {
+    target: NodeId,
+    attribute: String,
+    values: Vec<String>,
+    key_times: Option<Vec<f64>>,
+    transform_type: Option<String>,
+    additive: bool,
+    discrete: bool,
+    begin: f64,
+    duration: Option<f64>,
+    repeat: Repeat,
+    freeze: bool,
+}
+
+// Clock values in seconds, like "2s", "150ms" or "1.5"
+fn parse_clock(value: &str) -> Option<f64> {
+    let value = value.trim();
+
+    let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix("min") {
+        (number, 60.0)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 3600.0)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else {
+        (value, 1.0)
+    };
+
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|number| number * scale)
+}
+
+impl SmilAnimation {
+    fn parse(node: &Node, document: &Document, path: &str) -> Option<Self> {
+        let kind = node.tag_name().name();
+
+        let target = match node
+            .attribute((XLINK_NS, "href"))
+            .or(node.attribute("href"))
+        {
+            Some(href) => match document
+                .descendants()
+                .find(|other| other.attribute("id") == href.strip_prefix('#'))
+            {
+                Some(target) => target,
+                None => panic!(
+                    "SVG animation in '{}' targets missing element '{}'",
+                    path, href
+                ),
+            },
+            None => node.parent_element()?,
+        };
+
+        let (attribute, transform_type) = match kind {
+            "animateTransform" => (
+                node.attribute("attributeName")
+                    .unwrap_or("transform")
+                    .to_string(),
+                Some(node.attribute("type").unwrap_or("translate").to_string()),
+            ),
+            // Motion paths are not supported
+            "animateMotion" => return None,
+            _ => match node.attribute("attributeName") {
+                Some(attribute) => (attribute.to_string(), None),
+                None => panic!("SVG animation in '{}' is missing 'attributeName'", path),
+            },
+        };
+
+        let base = attribute_value(&target, &attribute);
+
+        let values = match node.attribute("values") {
+            Some(values) => values
+                .split(';')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<_>>(),
+            None => {
+                let to = node.attribute("to").map(|to| to.to_string());
+                let from = node
+                    .attribute("from")
+                    .map(|from| from.to_string())
+                    .or(if transform_type.is_none() { base } else { None });
+
+                match (kind, from, to) {
+                    ("set", _, Some(to)) => vec![to],
+                    (_, Some(from), Some(to)) => vec![from, to],
+                    (_, None, Some(to)) => vec![to],
+                    _ => panic!(
+                        "SVG animation of '{}' in '{}' has no 'values' or 'to'",
+                        attribute, path
+                    ),
+                }
+            }
+        };
+
+        if values.is_empty() {
+            panic!(
+                "SVG animation of '{}' in '{}' has empty 'values'",
+                attribute, path
+            );
+        }
+
+        let key_times = node.attribute("keyTimes").map(|key_times| {
+            let key_times = key_times
+                .split(';')
+                .filter(|key_time| !key_time.trim().is_empty())
+                .map(|key_time| match key_time.trim().parse::<f64>() {
+                    Ok(key_time) => key_time,
+                    Err(_) => panic!(
+                        "SVG animation of '{}' in '{}' has invalid 'keyTimes'",
+                        attribute, path
+                    ),
+                })
+                .collect::<Vec<_>>();
+
+            if key_times.len() != values.len() {
+                panic!(
+                    "SVG animation of '{}' in '{}' has {} 'keyTimes' for {} values",
+                    attribute,
+                    path,
+                    key_times.len(),
+                    values.len()
+                );
+            }
+
+            key_times
+        });
+
+        let begin = match node.attribute("begin") {
+            Some(begin) => match parse_clock(begin.split(';').next().unwrap_or("0")) {
+                Some(begin) => begin,
+                None => panic!(
+                    "SVG animation of '{}' in '{}' has unsupported 'begin' '{}' (only clock values)",
+                    attribute, path, begin
+                ),
+            },
+            None => 0.0,
+        };
+
+        let duration = match node.attribute("dur") {
+            Some("indefinite") | None => None,
+            Some(duration) => match parse_clock(duration) {
+                Some(duration) if duration > 0.0 => Some(duration),
+                _ => panic!(
+                    "SVG animation of '{}' in '{}' has invalid 'dur' '{}'",
+                    attribute, path, duration
+                ),
+            },
+        };
+
+        let repeat = match node.attribute("repeatCount") {
+            Some("indefinite") => Repeat::Indefinite,
+            Some(count) => match count.parse::<f64>() {
+                Ok(count) if count > 0.0 => Repeat::Count(count),
+                _ => panic!(
+                    "SVG animation of '{}' in '{}' has invalid 'repeatCount' '{}'",
+                    attribute, path, count
+                ),
+            },
+            None => Repeat::Count(1.0),
+        };
+
+        Some(Self {
+            target: target.id(),
+            attribute,
+            values,
+            key_times,
+            transform_type,
+            additive: node.attribute("additive") == Some("sum"),
+            discrete: kind == "set" || node.attribute("calcMode") == Some("discrete"),
+            begin,
+            duration,
+            repeat,
+            freeze: node.attribute("fill") == Some("freeze"),
+        })
+    }
+
+    // End of the animation in seconds, if it ever ends
+    fn end(&self) -> Option<f64> {
+        match (&self.repeat, self.duration) {
+            (Repeat::Count(count), Some(duration)) => Some(self.begin + duration * count),
+            (Repeat::Indefinite, Some(duration)) => Some(self.begin + duration),
+            _ => None,
+        }
+    }
+
+    // Progress through the simple duration at a time, or None if the animation has no effect
+    fn progress(&self, time: f64) -> Option<f64> {
+        if time < self.begin {
+            return None;
+        }
+
+        let duration = match self.duration {
+            Some(duration) => duration,
+            None => return Some(1.0),
+        };
+
+        let active = time - self.begin;
+        if let Repeat::Count(count) = self.repeat {
+            if active >= duration * count {
+                if !self.freeze {
+                    return None;
+                }
+
+                let end = count.fract();
+                return Some(if end == 0.0 { 1.0 } else { end });
+            }
+        }
+
+        Some((active % duration) / duration)
+    }
+
+    fn value(&self, progress: f64) -> String {
+        let count = self.values.len();
+        if count == 1 {
+            return self.values[0].clone();
+        }
+
+        let key_times = match &self.key_times {
+            Some(key_times) => key_times.clone(),
+            None if self.discrete => (0..count)
+                .map(|index| index as f64 / count as f64)
+                .collect(),
+            None => (0..count)
+                .map(|index| index as f64 / (count - 1) as f64)
+                .collect(),
+        };
+
+        let index = key_times
+            .iter()
+            .rposition(|key_time| *key_time <= progress)
+            .unwrap_or(0);
+
+        if self.discrete || index + 1 >= count {
+            return self.values[index].clone();
+        }
+
+        let span = key_times[index + 1] - key_times[index];
+        let local = if span > 0.0 {
+            (progress - key_times[index]) / span
+        } else {
+            1.0
+        };
+
+        interpolate(&self.values[index], &self.values[index + 1], local)
+    }
+}
+
+fn parse_hex(value: &str) -> Option<[f64; 3]> {
+    let hex = value.trim().strip_prefix('#')?;
+
+    let hex = match hex.len() {
+        3 => hex.chars().flat_map(|c| [c, c]).collect::<String>(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+    Some([channel(0)? as f64, channel(2)? as f64, channel(4)? as f64])
+}
+
+// Interpolates every number (or hex color) when both values have the same shape,
+// otherwise switches halfway
+fn interpolate(from: &str, to: &str, amount: f64) -> String {
+    if let (Some(from), Some(to)) = (parse_hex(from), parse_hex(to)) {
+        let channel = |index: usize| (from[index] + (to[index] - from[index]) * amount).round();
+        return format!(
+            "#{:02x}{:02x}{:02x}",
+            channel(0) as u8,
+            channel(1) as u8,
+            channel(2) as u8
+        );
+    }
+
+    let number = Regex::new(r"[-+]?(\d+\.?\d*|\.\d+)([eE][-+]?\d+)?").unwrap();
+
+    let from_numbers = number.find_iter(from).collect::<Vec<_>>();
+    let to_numbers = number.find_iter(to).collect::<Vec<_>>();
+
+    if from_numbers.is_empty()
+        || from_numbers.len() != to_numbers.len()
+        || number.replace_all(from, "0") != number.replace_all(to, "0")
+    {
+        return if amount < 0.5 { from } else { to }.to_string();
+    }
+
+    let mut result = String::new();
+    let mut last = 0;
+    for (from_match, to_match) in from_numbers.iter().zip(to_numbers.iter()) {
+        let start: f64 = from_match.as_str().parse().unwrap();
+        let end: f64 = to_match.as_str().parse().unwrap();
+
+        result.push_str(&from[last..from_match.start()]);
+        result.push_str(&format!("{}", start + (end - start) * amount));
+        last = from_match.end();
+    }
+    result.push_str(&from[last..]);
+
+    result
+}
+
+fn is_animation(node: &Node) -> bool {
+    node.tag_name().namespace() == Some(SVG_NS)
+        && ANIMATION_ELEMENTS.contains(&node.tag_name().name())
+}
+
+// Samples SMIL animations into static frames, returns each frame with its duration in milliseconds
+pub fn sample_smil(
+    svg: &str,
+    frame_rate: u32,
+    duration: Option<u32>,
+    path: &str,
+) -> Vec<(String, u32)> {
+    let document = match Document::parse(svg) {
+        Ok(document) => document,
+        Err(err) => panic!("Error parsing animated SVG file at '{}': {}", path, err),
+    };
+
+    let animations = document
+        .descendants()
+        .filter(is_animation)
+        .filter_map(|node| SmilAnimation::parse(&node, &document, path))
+        .collect::<Vec<_>>();
+
+    if animations.is_empty() {
+        panic!(
+            "SVG file at '{}' does not contain any SMIL animations",
+            path
+        );
+    }
+
+    let duration = match duration {
+        Some(duration) => duration as f64 / 1000.0,
+        None => match animations
+            .iter()
+            .filter_map(|animation| animation.end())
+            .reduce(f64::max)
+        {
+            Some(duration) => duration,
+            None => panic!(
+                "Animations in SVG file at '{}' never end, set 'animation.duration'",
+                path
+            ),
+        },
+    };
+
+    let frame_count = ((duration * frame_rate as f64).ceil() as usize).max(1);
+    let frame_duration = (1000.0 / frame_rate as f64).round() as u32;
+
+    (0..frame_count)
+        .map(|frame| {
+            let time = frame as f64 / frame_rate as f64;
+
+            // Later animations override earlier ones on the same attribute
+            let mut overrides: HashMap<(NodeId, String), String> = HashMap::new();
+            for animation in &animations {
+                let progress = match animation.progress(time) {
+                    Some(progress) => progress,
+                    None => continue,
+                };
+
+                let mut value = animation.value(progress);
+
+                if let Some(transform_type) = &animation.transform_type {
+                    value = format!("{}({})", transform_type, value);
+                }
+
+                if animation.additive {
+                    let key = (animation.target, animation.attribute.clone());
+                    let base = match overrides.get(&key) {
+                        Some(base) => Some(base.clone()),
+                        None => attribute_value(
+                            &document.get_node(animation.target).unwrap(),
+                            &animation.attribute,
+                        ),
+                    };
+
+                    if let Some(base) = base {
+                        value = format!("{} {}", base, value);
+                    }
+                }
+
+                overrides.insert((animation.target, animation.attribute.clone()), value);
+            }
+
+            let mut output = String::new();
+            write_node(
+                &document.root_element(),
+                &overrides,
+                &is_animation,
+                &mut output,
+            );
+
+            (output, frame_duration)
+        })
+        .collect()
+}
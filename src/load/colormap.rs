@@ -1,130 +1,628 @@
+use serde::Serialize;
+use std::fs;
+
+use crate::load::manifest::{parse_color, Colormap, ColormapEntry, Ramp};
+use crate::load::svg::Recolor;
 use crate::load::Emoji;
 use crate::Pack;
-use resvg::usvg::Color;
 
-fn parse_hex_str(hex_str: &str) -> Color {
-    let hex_str = hex_str.trim_start_matches("#");
+#[derive(Serialize, Debug)]
+struct UnusedColors {
+    colormap: String,
+    colors: Vec<String>,
+}
+
+// Colormap source colors missing from an emoji, and emoji colors no colormap entry covers
+#[derive(Serialize, Debug)]
+pub struct ColormapReport {
+    emoji: String,
+    src: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unused: Vec<UnusedColors>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    uncovered: Vec<String>,
+}
+
+fn srgb_to_linear(channel: u8) -> f64 {
+    let channel = channel as f64 / 255.0;
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(channel: f64) -> f64 {
+    if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// https://bottosson.github.io/posts/oklab/
+fn srgb_to_oklab([r, g, b, _]: [u8; 4]) -> [f64; 3] {
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+// Linear sRGB channels, which can be out of gamut
+fn oklab_to_linear([lightness, a, b]: [f64; 3]) -> [f64; 3] {
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+}
+
+// Changes the OKLCH lightness, reducing chroma until the color fits in sRGB
+fn shade(color: [u8; 4], lightness: f64) -> [u8; 4] {
+    let [base_lightness, a, b] = srgb_to_oklab(color);
+    let lightness = (base_lightness + lightness).clamp(0.0, 1.0);
+    let (chroma, hue) = (a.hypot(b), b.atan2(a));
+
+    let to_linear =
+        |chroma: f64| oklab_to_linear([lightness, chroma * hue.cos(), chroma * hue.sin()]);
+    let in_gamut = |linear: [f64; 3]| {
+        linear
+            .iter()
+            .all(|channel| (-0.0001..=1.0001).contains(channel))
+    };
+
+    let mut linear = to_linear(chroma);
+    if !in_gamut(linear) {
+        let (mut low, mut high) = (0.0, chroma);
+        for _ in 0..24 {
+            let middle = (low + high) / 2.0;
+            if in_gamut(to_linear(middle)) {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+
+        linear = to_linear(low);
+    }
+
+    let [r, g, b] =
+        linear.map(|channel| (linear_to_srgb(channel.clamp(0.0, 1.0)) * 255.0).round() as u8);
+    [r, g, b, color[3]]
+}
+
+// Every combination of one colormap per axis, the last axis varying fastest
+fn combinations(axes: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut combinations: Vec<Vec<usize>> = vec![Vec::new()];
+    for axis in axes {
+        combinations = combinations
+            .iter()
+            .flat_map(|combination| {
+                axis.iter().map(move |colormap| {
+                    let mut combination = combination.clone();
+                    combination.push(*colormap);
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    combinations
+}
+
+// Replaces %field.N with the value of axis N, and %field with the values of every axis
+fn substitute(
+    text: &str,
+    field: &str,
+    values: &[(&String, Option<&String>)],
+    emoji_name: &str,
+) -> String {
+    let placeholder = format!("%{}", field);
+    if !text.contains(&placeholder) {
+        return text.to_string();
+    }
+
+    let value = |axis: usize| match values[axis] {
+        (_, Some(value)) => value.as_str(),
+        (colormap_name, None) => panic!(
+            "Emoji '{}' uses {}, but colormap '{}' does not have a {}",
+            emoji_name, placeholder, colormap_name, field
+        ),
+    };
 
-    let r = u8::from_str_radix(&hex_str[0..2], 16).unwrap();
-    let g = u8::from_str_radix(&hex_str[2..4], 16).unwrap();
-    let b = u8::from_str_radix(&hex_str[4..6], 16).unwrap();
+    // From the last axis, so %label.1 doesn't match the start of %label.10
+    let mut text = text.to_string();
+    for axis in (0..values.len()).rev() {
+        let axis_placeholder = format!("{}.{}", placeholder, axis + 1);
+        if text.contains(&axis_placeholder) {
+            text = text.replace(&axis_placeholder, value(axis));
+        }
+    }
 
-    Color {
-        red: r,
-        green: g,
-        blue: b,
+    if text.contains(&placeholder) {
+        let values = (0..values.len()).map(value).collect::<Vec<_>>();
+        text = text.replace(&placeholder, &values.concat());
     }
+
+    text
 }
 
 impl Pack {
+    // Adds the entries derived from the base color of each colormap with a ramp,
+    // entries set explicitly take precedence
+    fn expand_ramps(&mut self) {
+        for colormap in self.colormaps.iter_mut() {
+            let name = &colormap.name;
+            let (base, ramp) = match (&colormap.base, &colormap.ramp) {
+                (Some(base), Some(ramp)) => (base, ramp),
+                _ => continue,
+            };
+
+            let base = match parse_color(base) {
+                Some(base) => base,
+                None => panic!(
+                    "Colormap '{}' has an invalid base color '{}' (must be a hex, rgb(), hsl() or named color)",
+                    name, base
+                ),
+            };
+
+            let shades = match ramp {
+                Ramp::Named(ramp) => match self.ramps[colormap.scope].get(ramp) {
+                    Some(shades) => shades,
+                    None => panic!(
+                        "Colormap '{}' uses ramp '{}' which is undefined in {:?}",
+                        name, ramp, colormap.manifest_path
+                    ),
+                },
+                Ramp::Shades(shades) => shades,
+            };
+
+            for (source, lightness) in shades {
+                let [r, g, b, a] = shade(base, *lightness);
+                let target = if a == 255 {
+                    format!("#{:02X}{:02X}{:02X}", r, g, b)
+                } else {
+                    format!("#{:02X}{:02X}{:02X}{:02X}", r, g, b, a)
+                };
+
+                colormap
+                    .entries
+                    .entry(source.clone())
+                    .or_insert(ColormapEntry {
+                        color: target,
+                        opacity: None,
+                        properties: None,
+                    });
+            }
+        }
+    }
+
     pub fn resolve_colormaps(&mut self) {
+        self.expand_ramps();
+
         let mut new_emojis: Vec<Emoji> = Vec::new();
+        let mut warnings: Vec<String> = Vec::new();
 
         for emoji in self.emojis.clone() {
             if emoji.colormaps.len() > 0 {
-                for colormap_name in &emoji.colormaps {
+                let mut colors = emoji.svg.as_ref().unwrap().colors();
+                for (frame, _) in &emoji.frames {
+                    for color in frame.colors() {
+                        if !colors.contains(&color) {
+                            colors.push(color);
+                        }
+                    }
+                }
+
+                let mut covered: Vec<[u8; 3]> = Vec::new();
+                let mut unused: Vec<UnusedColors> = Vec::new();
+
+                if emoji.colormaps.iter().any(|axis| axis.is_empty()) {
+                    panic!("Emoji '{}' has an empty colormap axis", emoji.name);
+                }
+
+                for combination in combinations(&emoji.colormaps) {
                     let mut emoji = emoji.clone();
 
-                    let colormap = match self.colormaps.get(colormap_name) {
-                        Some(colormap) => colormap,
-                        None => panic!(
-                            "Emoji '{}' uses colormap '{}' which is undefined",
-                            emoji.name, colormap_name
-                        ),
-                    };
+                    let colormaps = combination
+                        .iter()
+                        .map(|colormap| {
+                            let colormap = &self.colormaps[*colormap];
+                            (&colormap.name, colormap)
+                        })
+                        .collect::<Vec<_>>();
 
-                    if emoji.name.contains("%label") {
-                        let label = match &colormap.label {
-                            Some(label) => label,
-                            None => panic!(
-                                "Emoji '{}' uses %label, but colormap '{}' does not have a label ",
-                                emoji.name, colormap_name
-                            ),
-                        };
+                    let field = |get: fn(&Colormap) -> Option<&String>| {
+                        colormaps
+                            .iter()
+                            .map(|(colormap_name, colormap)| (*colormap_name, get(colormap)))
+                            .collect::<Vec<_>>()
+                    };
 
-                        emoji.name = emoji.name.replace("%label", label);
-                    }
+                    emoji.name = substitute(
+                        &emoji.name,
+                        "label",
+                        &field(|colormap| colormap.label.as_ref()),
+                        &emoji.name,
+                    );
 
                     for shortcode in &mut emoji.shortcodes {
-                        if shortcode.contains("%shortcode") {
-                            let colormap_shortcode = match &colormap.shortcode {
-                                Some(colormap_shortcode) => colormap_shortcode,
-                                None => panic!("Emoji '{}' uses %shortcode, but colormap '{}' does not have a shortcode ", emoji.name, colormap_name),
-                            };
-
-                            *shortcode = shortcode.replace("%shortcode", colormap_shortcode);
-                        }
+                        *shortcode = substitute(
+                            shortcode,
+                            "shortcode",
+                            &field(|colormap| colormap.shortcode.as_ref()),
+                            &emoji.name,
+                        );
                     }
 
+                    emoji.description = substitute(
+                        &emoji.description,
+                        "description",
+                        &field(|colormap| colormap.description.as_ref()),
+                        &emoji.name,
+                    );
+
                     if let Some(codepoint) = &mut emoji.codepoint {
                         let mut new_codepoint: Vec<String> = Vec::new();
 
                         for codepoint in codepoint.iter() {
-                            if codepoint == "%codepoint" {
-                                let colormap_codepoint = match &colormap.codepoint {
-                                    Some(colormap_codepoint) => colormap_codepoint,
-                                    None => panic!("Emoji '{}' uses %codepoint, but colormap '{}' does not have a codepoint ", emoji.name, colormap_name),
-                                };
-
-                                for codepoint in colormap_codepoint {
-                                    new_codepoint.push(codepoint.to_owned());
+                            // %codepoint for every axis, %codepoint.N for a single one
+                            let axes = if codepoint == "%codepoint" {
+                                (0..colormaps.len()).collect()
+                            } else {
+                                match codepoint
+                                    .strip_prefix("%codepoint.")
+                                    .and_then(|axis| axis.parse::<usize>().ok())
+                                {
+                                    Some(axis) if (1..=colormaps.len()).contains(&axis) => {
+                                        vec![axis - 1]
+                                    }
+                                    _ => {
+                                        new_codepoint.push(codepoint.clone());
+                                        continue;
+                                    }
                                 }
+                            };
 
-                                continue;
+                            for axis in axes {
+                                let (colormap_name, colormap) = colormaps[axis];
+                                match &colormap.codepoint {
+                                    Some(colormap_codepoint) => new_codepoint.extend(colormap_codepoint.iter().cloned()),
+                                    None => panic!("Emoji '{}' uses {}, but colormap '{}' does not have a codepoint", emoji.name, codepoint, colormap_name),
+                                }
                             }
-
-                            new_codepoint.push(codepoint.clone());
                         }
 
                         *codepoint = new_codepoint;
                     }
 
-                    if emoji.description.contains("%description") {
-                        let colormap_description = match &colormap.description {
-                            Some(colormap_description) => colormap_description,
-                            None => panic!("Emoji '{}' uses %description, but colormap '{}' does not have a description ", emoji.name, colormap_name),
-                        };
+                    // The entries of every axis, with their axis and source key
+                    let mut colormap_entries: Vec<Recolor> = Vec::new();
+                    let mut entry_keys: Vec<(usize, &String)> = Vec::new();
+                    for (axis, (colormap_name, colormap)) in colormaps.iter().enumerate() {
+                        let palette = emoji.palettes.get(axis);
+
+                        for (entry, value) in &colormap.entries {
+                            let key = match palette.and_then(|palette| palette.get(entry)) {
+                                Some(key) => key,
+                                None => entry,
+                            };
+
+                            let source = match parse_color(key) {
+                                Some(source) => source,
+                                None if key != entry => panic!(
+                                    "Emoji '{}' has an invalid palette color '{}' for '{}' of colormap '{}' (must be a hex, rgb(), hsl() or named color)",
+                                    emoji.name, key, entry, colormap_name
+                                ),
+                                None => panic!(
+                                    "Colormap '{}' has an invalid source color '{}' (must be a hex, rgb(), hsl() or named color)",
+                                    colormap_name, key
+                                ),
+                            };
+
+                            let target = match parse_color(&value.color) {
+                                Some(target) => target,
+                                None => panic!(
+                                    "Colormap '{}' has an invalid target color '{}' for '{}' (must be a hex, rgb(), hsl() or named color)",
+                                    colormap_name, value.color, entry
+                                ),
+                            };
+
+                            let recolor = Recolor {
+                                from: source,
+                                to: target,
+                                opacity: value.opacity,
+                                properties: value.properties.clone(),
+                            };
+
+                            if let Some(index) = colormap_entries
+                                .iter()
+                                .position(|other| other.overlaps(&recolor))
+                            {
+                                let (other_axis, other_key) = entry_keys[index];
+                                if other_axis == axis {
+                                    panic!(
+                                        "Colormap '{}' maps the same source color twice, in entries '{}' and '{}'",
+                                        colormap_name, other_key, key
+                                    );
+                                }
+
+                                panic!(
+                                    "Emoji '{}' uses colormaps '{}' and '{}' on different axes, which both map the source color '{}', set 'palettes' to tell them apart",
+                                    emoji.name, colormaps[other_axis].0, colormap_name, key
+                                );
+                            }
 
-                        emoji.description = emoji
-                            .description
-                            .replace("%description", colormap_description);
+                            colormap_entries.push(recolor);
+                            entry_keys.push((axis, key));
+                        }
                     }
 
-                    let mut colormap_entries: Vec<(Color, Color)> = Vec::new();
-                    for (key, value) in &colormap.entries {
-                        if !key.starts_with("#") {
-                            panic!(
-                                "Colormap '{}' has an invalid source color '{}'",
-                                colormap_name, key
-                            );
+                    let (mut found, mut dropped) = emoji
+                        .svg
+                        .as_mut()
+                        .unwrap()
+                        .replace_colors(&colormap_entries);
+                    for (frame, _) in emoji.frames.iter_mut() {
+                        let (frame_found, frame_dropped) = frame.replace_colors(&colormap_entries);
+                        found = found
+                            .iter()
+                            .zip(frame_found)
+                            .map(|(a, b)| *a || b)
+                            .collect();
+                        for property in frame_dropped {
+                            if !dropped.contains(&property) {
+                                dropped.push(property);
+                            }
                         }
+                    }
+
+                    for property in &dropped {
+                        warnings.push(format!(
+                            "Emoji '{}' ({:?}) recolors '{}' with a different alpha, which can't be applied without an opacity property, only the color is changed",
+                            emoji.name, emoji.src, property
+                        ));
+                    }
 
-                        if !value.starts_with("#") {
-                            panic!(
-                                "Colormap '{}' has an invalid target color '{}'",
-                                colormap_name, value
-                            );
+                    // Reported once per colormap, not for every combination
+                    for (axis, (colormap_name, _)) in colormaps.iter().enumerate() {
+                        // Sorted, entries are in no particular order and the report is meant to be diffed
+                        let mut unused_colors = entry_keys
+                            .iter()
+                            .zip(&found)
+                            .filter(|((entry_axis, _), found)| *entry_axis == axis && !**found)
+                            .map(|((_, key), _)| key.to_string())
+                            .collect::<Vec<_>>();
+                        unused_colors.sort();
+
+                        if unused_colors.is_empty()
+                            || unused.iter().any(|other| {
+                                other.colormap == **colormap_name && other.colors == unused_colors
+                            })
+                        {
+                            continue;
                         }
 
-                        let source = parse_hex_str(&key);
-                        let target = parse_hex_str(&value);
+                        for key in &unused_colors {
+                            warnings.push(format!(
+                                "Colormap '{}' source color '{}' does not occur in emoji '{}' ({:?})",
+                                colormap_name, key, emoji.name, emoji.src
+                            ));
+                        }
 
-                        colormap_entries.push((source, target));
+                        unused.push(UnusedColors {
+                            colormap: colormap_name.to_string(),
+                            colors: unused_colors,
+                        });
                     }
 
-                    emoji.colormaps.clear();
+                    covered.extend(
+                        colormap_entries
+                            .iter()
+                            .map(|recolor| [recolor.from[0], recolor.from[1], recolor.from[2]]),
+                    );
 
-                    let mut svg = emoji.svg.take().unwrap();
-                    svg.replace_colors(colormap_entries);
-                    emoji.svg = Some(svg);
+                    emoji.colormaps.clear();
+                    emoji.palettes.clear();
 
                     new_emojis.push(emoji);
                 }
+
+                let mut uncovered = colors
+                    .iter()
+                    .filter(|color| !covered.contains(color))
+                    .map(|color| format!("#{:02X}{:02X}{:02X}", color[0], color[1], color[2]))
+                    .collect::<Vec<_>>();
+                uncovered.sort();
+
+                if !unused.is_empty() || !uncovered.is_empty() {
+                    // The report covers every combination, so the name is left without labels
+                    let empty = String::new();
+                    let name = substitute(
+                        &emoji.name,
+                        "label",
+                        &vec![(&empty, Some(&empty)); emoji.colormaps.len()],
+                        &emoji.name,
+                    );
+
+                    self.colormap_report.push(ColormapReport {
+                        emoji: name,
+                        src: emoji.src.display().to_string(),
+                        unused,
+                        uncovered,
+                    });
+                }
             } else {
                 new_emojis.push(emoji);
             }
         }
 
+        for warning in warnings {
+            self.logger.warn(&warning);
+        }
+
         self.emojis = new_emojis;
     }
+
+    pub fn save_colormap_report(&mut self) {
+        if self.colormap_report.is_empty() {
+            return;
+        }
+
+        let path = self.output_path.join("colormap-report.json");
+        let report = serde_json::to_string_pretty(&self.colormap_report).unwrap();
+
+        match fs::write(&path, report) {
+            Ok(_) => self.logger.info(&format!(
+                "Wrote colormap report for {} emojis to {:?}",
+                self.colormap_report.len(),
+                path
+            )),
+            Err(err) => panic!("Failed to write colormap report to {:?}: {}", path, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_shade_keeps_the_color() {
+        for color in [
+            [0xF1, 0x0D, 0xC3, 0xFF],
+            [0x64, 0x00, 0x82, 0x80],
+            [0x00, 0x00, 0x00, 0xFF],
+            [0xFF, 0xFF, 0xFF, 0x00],
+        ] {
+            assert_eq!(shade(color, 0.0), color);
+        }
+    }
+
+    #[test]
+    fn shades_change_oklch_lightness() {
+        let base = [0x70, 0x5D, 0x17, 0xFF];
+        let [base_lightness, ..] = srgb_to_oklab(base);
+
+        for amount in [-0.2, -0.1, 0.1, 0.2] {
+            let shaded = shade(base, amount);
+            let [lightness, ..] = srgb_to_oklab(shaded);
+            assert!(
+                (lightness - (base_lightness + amount)).abs() < 0.01,
+                "{:?} shaded by {} has lightness {}, expected {}",
+                shaded,
+                amount,
+                lightness,
+                base_lightness + amount
+            );
+        }
+    }
+
+    #[test]
+    fn shades_keep_alpha_and_clamp_lightness() {
+        assert_eq!(
+            shade([0x80, 0x40, 0x20, 0x7F], 1.0),
+            [0xFF, 0xFF, 0xFF, 0x7F]
+        );
+        assert_eq!(
+            shade([0x80, 0x40, 0x20, 0x7F], -1.0),
+            [0x00, 0x00, 0x00, 0x7F]
+        );
+    }
+
+    #[test]
+    fn shades_reduce_chroma_to_stay_in_gamut() {
+        // Pure blue can't be made much lighter at the same chroma
+        let blue = [0x00, 0x00, 0xFF, 0xFF];
+        let [base_lightness, a, b] = srgb_to_oklab(blue);
+        let shaded = shade(blue, 0.3);
+        let [lightness, shaded_a, shaded_b] = srgb_to_oklab(shaded);
+
+        assert!((lightness - (base_lightness + 0.3)).abs() < 0.01);
+        assert!(shaded_a.hypot(shaded_b) < a.hypot(b));
+        assert!((shaded_b.atan2(shaded_a) - b.atan2(a)).abs() < 0.05);
+    }
+
+    #[test]
+    fn combines_one_colormap_per_axis() {
+        assert_eq!(combinations(&[vec![0, 1]]), [vec![0], vec![1]]);
+        assert_eq!(
+            combinations(&[vec![0, 1], vec![2, 3, 4]]),
+            [
+                vec![0, 2],
+                vec![0, 3],
+                vec![0, 4],
+                vec![1, 2],
+                vec![1, 3],
+                vec![1, 4]
+            ]
+        );
+        assert_eq!(combinations(&[]), [Vec::<usize>::new()]);
+    }
+
+    fn labels(labels: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        labels
+            .iter()
+            .map(|(name, label)| (name.to_string(), label.map(str::to_string)))
+            .collect()
+    }
+
+    fn run(text: &str, labels: &[(String, Option<String>)]) -> String {
+        let values = labels
+            .iter()
+            .map(|(name, label)| (name, label.as_ref()))
+            .collect::<Vec<_>>();
+        substitute(text, "label", &values, "emoji")
+    }
+
+    #[test]
+    fn substitutes_every_axis() {
+        let labels = labels(&[("skin", Some(" light")), ("hair", Some(" red"))]);
+
+        assert_eq!(run("Person%label", &labels), "Person light red");
+        assert_eq!(run("Person", &labels), "Person");
+    }
+
+    #[test]
+    fn substitutes_single_axes() {
+        let labels = labels(&[("skin", Some(" light")), ("hair", Some(" red"))]);
+
+        assert_eq!(
+            run("Person%label.2 and%label.1", &labels),
+            "Person red and light"
+        );
+        assert_eq!(run("%label.1%label", &labels), " light light red");
+    }
+
+    #[test]
+    fn substitutes_ten_axes_and_more() {
+        let labels = (1..=10)
+            .map(|axis| (format!("axis{}", axis), Some(format!("<{}>", axis))))
+            .collect::<Vec<_>>();
+
+        assert_eq!(run("%label.10 %label.1", &labels), "<10> <1>");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Emoji 'emoji' uses %label, but colormap 'hair' does not have a label"
+    )]
+    fn rejects_missing_labels() {
+        let labels = labels(&[("skin", Some(" light")), ("hair", None)]);
+
+        // Only the axes that are used need a label
+        assert_eq!(run("Person%label.1", &labels), "Person light");
+        run("Person%label", &labels);
+    }
 }
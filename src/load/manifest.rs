@@ -1,8 +1,19 @@
+use oxipng::{RowFilter, StripChunks};
 use std::{collections::HashMap, fs, path::PathBuf};
+use toml::{Table, Value};
 use zip::CompressionMethod;
 
-use crate::load::{svg::Svg, Pack};
-use crate::process::encode::{EncodeTarget, OxiPngMode};
+use crate::load::{
+    svg::{Svg, PAINT_PROPERTIES},
+    variable, Pack,
+};
+use crate::process::effects::Effect;
+use crate::process::encode::{
+    AvifColorSpace, AvifOptions, EncodeTarget, OxiPngMode, OxiPngOptions,
+};
+use crate::process::font::FontFormat;
+use crate::process::rasterize::{AspectMode, RenderOptions};
+use crate::process::resample::DownscaleFilter;
 
 #[derive(Clone, Debug)]
 pub enum TarCompression {
@@ -26,18 +37,38 @@ pub enum FilenameFormat {
     Codepoint,
 }
 
+#[derive(Clone, Debug)]
+pub enum SizeNaming {
+    Directory,
+    Suffix,
+}
+
 #[derive(Clone, Debug)]
 pub struct OutputStructure {
     pub container: Container,
     pub filenames: FilenameFormat,
     pub flat: bool,
+    pub sizes: SizeNaming,
 }
 
 #[derive(Clone, Debug)]
 pub enum OutputFormat {
     None,
     Svg,
-    Raster { format: EncodeTarget, size: u32 },
+    Raster {
+        format: EncodeTarget,
+        sizes: Vec<u32>,
+    },
+    // Keeps the smallest candidate for every file, candidates are named after their format by default
+    Auto {
+        candidates: Vec<(String, EncodeTarget)>,
+        min_psnr: Option<f64>,
+        sizes: Vec<u32>,
+    },
+    Font {
+        format: FontFormat,
+        family: String,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -46,23 +77,77 @@ pub struct Target {
     pub tags: Vec<String>,
     pub include_tags: Vec<String>,
     pub output_structure: OutputStructure,
-    pub output_format: OutputFormat,
+    pub output_formats: Vec<OutputFormat>,
+    pub render: RenderOptions,
+    pub effects: Vec<Effect>,
     pub include_files: Vec<PathBuf>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Colormap {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    // The manifest whose scope named ramps are resolved in
+    pub scope: usize,
     pub label: Option<String>,
     pub shortcode: Option<String>,
     pub codepoint: Option<Vec<String>>,
     pub description: Option<String>,
-    pub entries: HashMap<String, String>,
+    pub entries: HashMap<String, ColormapEntry>,
+    pub base: Option<String>,
+    pub ramp: Option<Ramp>,
+}
+
+// Opacity replaces the opacity of recolored paints, properties limits the paints that are recolored
+#[derive(Clone, Debug)]
+pub struct ColormapEntry {
+    pub color: String,
+    pub opacity: Option<f64>,
+    pub properties: Option<Vec<String>>,
+}
+
+// Lightness changes in OKLCH for each source color, applied to the base color of a colormap
+#[derive(Clone, Debug)]
+pub enum Ramp {
+    Named(String),
+    Shades(HashMap<String, f64>),
+}
+
+// The manifest is kept to point at the definition in errors
+#[derive(Clone, Debug)]
+pub struct Definition {
+    pub value: String,
+    pub manifest_path: PathBuf,
+    // The manifest whose scope the variables in the value are resolved in
+    pub scope: usize,
+}
+
+// Fields merged into the emojis that extend it
+struct Template {
+    name: String,
+    table: Table,
+    // The manifest whose scope templates extended by this one are looked up in
+    scope: usize,
+    manifest_path: PathBuf,
+}
+
+// Frame durations are in milliseconds
+#[derive(Clone, Debug)]
+pub enum Animation {
+    Frames(Vec<(PathBuf, u32)>),
+    Smil {
+        frame_rate: u32,
+        duration: Option<u32>,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Emoji {
     pub src: PathBuf,
+    pub manifest_path: PathBuf,
     pub svg: Option<Svg>,
+    pub animation: Option<Animation>,
+    pub frames: Vec<(Svg, u32)>,
     pub name: String,
     pub category: Vec<String>,
     pub description: String,
@@ -70,7 +155,840 @@ pub struct Emoji {
     pub codepoint: Option<Vec<String>>,
     pub root_codepoint: Option<Vec<String>>,
     pub shortcodes: Vec<String>,
-    pub colormaps: Vec<String>,
+    // One list of colormaps per axis, as indices into Pack::colormaps, every combination is generated
+    pub colormaps: Vec<Vec<usize>>,
+    // Source colors of each axis, replacing the colormap's own
+    pub palettes: Vec<HashMap<String, String>>,
+}
+
+// Hex (3, 4, 6 or 8 digits), rgb(), hsl() or a named color, as [r, g, b, a]
+pub fn parse_color(color: &str) -> Option<[u8; 4]> {
+    match color.trim().parse::<svgtypes::Color>() {
+        Ok(color) => Some([color.red, color.green, color.blue, color.alpha]),
+        Err(_) => None,
+    }
+}
+
+fn parse_colormap_entry(
+    entry: &Table,
+    name: &str,
+    key: &str,
+    manifest_path: &PathBuf,
+) -> ColormapEntry {
+    let color = match entry.get("color").and_then(|color| color.as_str()) {
+        Some(color) => color.to_string(),
+        None => panic!(
+            "Colormap '{}' entry '{}' is missing 'color' in {:?}",
+            name, key, manifest_path
+        ),
+    };
+
+    let opacity = match entry.get("opacity") {
+        Some(opacity) => match opacity.as_float() {
+            Some(opacity) if (0.0..=1.0).contains(&opacity) => Some(opacity),
+            _ => panic!("Colormap '{}' entry '{}' contains invalid 'opacity' '{}' (must be 0.0-1.0) in {:?}", name, key, opacity, manifest_path),
+        },
+        None => None,
+    };
+
+    let properties = match entry.get("properties") {
+        Some(properties) => match properties.as_array() {
+            Some(properties) => Some(
+                properties
+                    .iter()
+                    .map(|property| match property.as_str() {
+                        Some(property) if PAINT_PROPERTIES.iter().any(|(paint, _)| *paint == property) => property.to_string(),
+                        _ => panic!(
+                            "Colormap '{}' entry '{}' contains invalid property '{}' (must be one of {}) in {:?}",
+                            name,
+                            key,
+                            property,
+                            PAINT_PROPERTIES.map(|(paint, _)| paint).join(", "),
+                            manifest_path
+                        ),
+                    })
+                    .collect(),
+            ),
+            None => panic!("Colormap '{}' entry '{}' contains invalid 'properties' (must be an array) in {:?}", name, key, manifest_path),
+        },
+        None => None,
+    };
+
+    for key_name in entry.keys() {
+        if !["color", "opacity", "properties"].contains(&key_name.as_str()) {
+            panic!(
+                "Colormap '{}' entry '{}' contains unknown key '{}' in {:?}",
+                name, key, key_name, manifest_path
+            );
+        }
+    }
+
+    ColormapEntry {
+        color,
+        opacity,
+        properties,
+    }
+}
+
+// A variable can stand in for several codepoints or colormaps, separated by spaces
+fn split_components(value: &str) -> Vec<String> {
+    value.split_whitespace().map(str::to_string).collect()
+}
+
+fn parse_export(table: &Table, kind: &str, manifest_path: &PathBuf) -> bool {
+    match table.get("export") {
+        Some(export) => match export.as_bool() {
+            Some(export) => export,
+            None => panic!("{} 'export' is not a boolean in {:?}", kind, manifest_path),
+        },
+        None => false,
+    }
+}
+
+// Each manifest sees what the manifest including it sees, its own entries and the entries
+// exported by the manifests it includes, which take precedence
+fn scope_entries<T: Clone>(
+    parents: &[Option<usize>],
+    entries: &[Vec<(String, T, bool)>],
+    location: impl Fn(&T) -> PathBuf,
+    kind: &str,
+    warnings: &mut Vec<String>,
+) -> Vec<HashMap<String, T>> {
+    let mut scopes: Vec<HashMap<String, T>> = Vec::new();
+
+    for (index, parent) in parents.iter().enumerate() {
+        let mut scope = match parent {
+            Some(parent) => scopes[*parent].clone(),
+            None => HashMap::new(),
+        };
+
+        let exported = parents
+            .iter()
+            .enumerate()
+            .filter(|(_, parent)| **parent == Some(index))
+            .flat_map(|(child, _)| entries[child].iter().filter(|(_, _, export)| *export));
+
+        let mut added: Vec<(&String, &T)> = Vec::new();
+        for (name, value, _) in entries[index].iter().chain(exported) {
+            if let Some((_, other)) = added.iter().find(|(other, _)| *other == name) {
+                panic!(
+                    "{} '{}' is defined twice in the same scope, in {:?} and {:?}",
+                    kind,
+                    name,
+                    location(other),
+                    location(value)
+                );
+            }
+
+            added.push((name, value));
+        }
+
+        for (name, value) in added {
+            if let Some(other) = scope.get(name) {
+                if location(other) != location(value) {
+                    warnings.push(format!(
+                        "{} '{}' in {:?} shadows the one in {:?}",
+                        kind,
+                        name,
+                        location(value),
+                        location(other)
+                    ));
+                }
+            }
+
+            scope.insert(name.clone(), value.clone());
+        }
+
+        scopes.push(scope);
+    }
+
+    scopes
+}
+
+// Fields of an emoji or template replace those of the template it extends, except lists
+// set to `{ append = [...] }`, which are appended to
+fn extend_template(
+    table: &Table,
+    scope: usize,
+    templates: &[Template],
+    template_scopes: &[HashMap<String, usize>],
+    manifest_path: &PathBuf,
+    chain: &mut Vec<usize>,
+) -> Table {
+    let mut extended = match table.get("extends") {
+        Some(Value::String(name)) => {
+            let index = match template_scopes[scope].get(name) {
+                Some(index) => *index,
+                None => panic!(
+                    "Manifest extends template '{}', which is undefined in {:?}",
+                    name, manifest_path
+                ),
+            };
+            let template = &templates[index];
+
+            // Templates with the same name in different scopes are different templates
+            if chain.contains(&index) {
+                let cycle = chain
+                    .iter()
+                    .skip_while(|other| **other != index)
+                    .map(|other| templates[*other].name.as_str())
+                    .collect::<Vec<_>>();
+
+                panic!(
+                    "Template '{}' extends itself through {} -> {} in {:?}",
+                    name,
+                    cycle.join(" -> "),
+                    name,
+                    template.manifest_path
+                );
+            }
+
+            chain.push(index);
+            extend_template(
+                &template.table,
+                template.scope,
+                templates,
+                template_scopes,
+                &template.manifest_path,
+                chain,
+            )
+        }
+        Some(_) => panic!("Field 'extends' is not a string in {:?}", manifest_path),
+        None => Table::new(),
+    };
+
+    for (key, value) in table.iter() {
+        if key == "extends" {
+            continue;
+        }
+
+        let append = match value {
+            Value::Table(value) if key != "animation" && value.contains_key("append") => {
+                match (value.len(), value.get("append")) {
+                    (1, Some(Value::Array(append))) => append,
+                    _ => panic!(
+                        "Field '{}' must only contain an 'append' array in {:?}",
+                        key, manifest_path
+                    ),
+                }
+            }
+            _ => {
+                extended.insert(key.clone(), value.clone());
+                continue;
+            }
+        };
+
+        match extended.get_mut(key) {
+            Some(Value::Array(list)) => list.extend(append.iter().cloned()),
+            Some(_) => panic!(
+                "Field '{}' appends to a value that is not an array in {:?}",
+                key, manifest_path
+            ),
+            None => {
+                extended.insert(key.clone(), Value::Array(append.clone()));
+            }
+        }
+    }
+
+    extended
+}
+
+// "base", "lighten N%" or "darken N%"
+fn parse_shade(shade: &str) -> Option<f64> {
+    let shade = shade.trim();
+    if shade == "base" {
+        return Some(0.0);
+    }
+
+    let (direction, amount) = shade.split_once(' ')?;
+    let amount = amount
+        .trim()
+        .strip_suffix('%')?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    if !(0.0..=100.0).contains(&amount) {
+        return None;
+    }
+
+    match direction {
+        "lighten" => Some(amount / 100.0),
+        "darken" => Some(-amount / 100.0),
+        _ => None,
+    }
+}
+
+fn parse_shades<'a>(
+    shades: impl Iterator<Item = (&'a String, &'a Value)>,
+    name: &str,
+    manifest_path: &PathBuf,
+) -> HashMap<String, f64> {
+    shades
+        .map(|(key, value)| match value.as_str().and_then(parse_shade) {
+            Some(shade) => (key.to_string(), shade),
+            None => panic!("Ramp of '{}' contains invalid shade '{}' for '{}' (must be 'base', 'lighten N%' or 'darken N%') in {:?}", name, value, key, manifest_path),
+        })
+        .collect()
+}
+
+fn parse_output(output: &Table, name: &str, manifest_path: &PathBuf) -> OutputFormat {
+    // A single size, or a list of sizes
+    let sizes = match output.get("size") {
+        Some(size) => {
+            let values = match size.as_array() {
+                Some(values) => values.iter().collect::<Vec<_>>(),
+                None => vec![size],
+            };
+
+            if values.is_empty() {
+                panic!("Target contains empty 'output.size' in {:?}", manifest_path);
+            }
+
+            Some(
+                values
+                    .iter()
+                    .map(|size| match size.as_integer() {
+                        Some(size) => {
+                            if size < 0 {
+                                panic!(
+                                    "Target contains 'output.size' '{}' (under 0) in {:?}",
+                                    size, manifest_path
+                                );
+                            }
+
+                            if size > 65536 {
+                                panic!(
+                                    "Target contains 'output.size' '{}' (over 65536) in {:?}",
+                                    size, manifest_path
+                                );
+                            }
+
+                            size as u32
+                        }
+                        None => panic!(
+                            "Target contains invalid 'output.size' '{}' in {:?}",
+                            size, manifest_path
+                        ),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
+        None => None,
+    };
+
+    let compression = match output.get("compression") {
+        Some(compression) => match compression.as_float() {
+            Some(compression) => {
+                if compression < 0.0 {
+                    panic!("Target contains 'output.compression' '{}' (under 0.0) in {:?}", compression, manifest_path);
+                }
+
+                Some(compression)
+            },
+            None => panic!("Target contains invalid 'output.compression' '{}' (must contain a decimal point) in {:?}", compression, manifest_path),
+        },
+        None => None,
+    };
+
+    let lossless = match output.get("lossless") {
+        Some(lossless) => match lossless.as_bool() {
+            Some(lossless) => lossless,
+            None => panic!(
+                "Target contains invalid 'output.lossless' '{}' (must be a boolean) in {:?}",
+                lossless, manifest_path
+            ),
+        },
+        None => false,
+    };
+
+    let method = match output.get("method") {
+        Some(method) => match method.as_integer() {
+            Some(method) if (0..=6).contains(&method) => Some(method as u8),
+            _ => panic!(
+                "Target contains invalid 'output.method' '{}' (must be 0-6) in {:?}",
+                method, manifest_path
+            ),
+        },
+        None => None,
+    };
+
+    let distance = match output.get("distance") {
+        Some(distance) => match distance.as_float() {
+            Some(distance) if (0.1..=25.0).contains(&distance) => Some(distance as f32),
+            _ => panic!(
+                "Target contains invalid 'output.distance' '{}' (must be 0.1-25.0) in {:?}",
+                distance, manifest_path
+            ),
+        },
+        None => None,
+    };
+
+    let effort = match output.get("effort") {
+        Some(effort) => match effort.as_integer() {
+            Some(effort) if (1..=10).contains(&effort) => Some(effort as u8),
+            _ => panic!(
+                "Target contains invalid 'output.effort' '{}' (must be 1-10) in {:?}",
+                effort, manifest_path
+            ),
+        },
+        None => None,
+    };
+
+    let alpha_quality = match output.get("alpha_quality") {
+        Some(alpha_quality) => match alpha_quality.as_float() {
+            Some(alpha_quality) if (1.0..=100.0).contains(&alpha_quality) => {
+                Some(alpha_quality as f32)
+            }
+            _ => panic!(
+                "Target contains invalid 'output.alpha_quality' '{}' (must be 1.0-100.0) in {:?}",
+                alpha_quality, manifest_path
+            ),
+        },
+        None => None,
+    };
+
+    let speed = match output.get("speed") {
+        Some(speed) => match speed.as_integer() {
+            Some(speed) if (1..=10).contains(&speed) => speed as u8,
+            _ => panic!(
+                "Target contains invalid 'output.speed' '{}' (must be 1-10) in {:?}",
+                speed, manifest_path
+            ),
+        },
+        None => 1,
+    };
+
+    let depth = match output.get("depth") {
+        Some(depth) => match depth.as_integer() {
+            Some(depth) if depth == 8 || depth == 10 => Some(depth as u8),
+            _ => panic!(
+                "Target contains invalid 'output.depth' '{}' (must be 8 or 10) in {:?}",
+                depth, manifest_path
+            ),
+        },
+        None => None,
+    };
+
+    let color_space = match output.get("color_space") {
+        Some(color_space) => match color_space.as_str() {
+            Some("ycbcr") => AvifColorSpace::YCbCr,
+            Some("rgb") => AvifColorSpace::Rgb,
+            _ => panic!("Target contains invalid 'output.color_space' '{}' (must be 'ycbcr' or 'rgb') in {:?}", color_space, manifest_path),
+        },
+        None => AvifColorSpace::YCbCr,
+    };
+
+    let threads = match output.get("threads") {
+        Some(threads) => match threads.as_integer() {
+            Some(threads) if threads >= 1 => Some(threads as usize),
+            _ => panic!(
+                "Target contains invalid 'output.threads' '{}' (must be 1 or more) in {:?}",
+                threads, manifest_path
+            ),
+        },
+        None => None,
+    };
+
+    let colors = match output.get("colors") {
+        Some(colors) => match colors.as_integer() {
+            Some(colors) if (2..=256).contains(&colors) => colors as u16,
+            _ => panic!(
+                "Target contains invalid 'output.colors' '{}' (must be 2-256) in {:?}",
+                colors, manifest_path
+            ),
+        },
+        None => 256,
+    };
+
+    let dither = match output.get("dither") {
+        Some(dither) => match dither.as_bool() {
+            Some(dither) => dither,
+            None => panic!(
+                "Target contains invalid 'output.dither' '{}' (must be a boolean) in {:?}",
+                dither, manifest_path
+            ),
+        },
+        None => true,
+    };
+
+    let mut oxipng_options = OxiPngOptions::default();
+
+    if let Some(preset) = output.get("preset") {
+        oxipng_options.preset = match preset.as_integer() {
+            Some(preset) if (0..=6).contains(&preset) => preset as u8,
+            _ => panic!(
+                "Target contains invalid 'output.preset' '{}' (must be 0-6) in {:?}",
+                preset, manifest_path
+            ),
+        };
+    }
+
+    if let Some(filters) = output.get("filters") {
+        let filters = match filters.as_array() {
+            Some(filters) if !filters.is_empty() => filters,
+            _ => panic!(
+                "Target contains invalid 'output.filters' '{}' (must be a non-empty array) in {:?}",
+                filters, manifest_path
+            ),
+        };
+
+        oxipng_options.filters = Some(
+            filters
+                .iter()
+                .map(|filter| match filter.as_str() {
+                    Some("none") => RowFilter::None,
+                    Some("sub") => RowFilter::Sub,
+                    Some("up") => RowFilter::Up,
+                    Some("average") => RowFilter::Average,
+                    Some("paeth") => RowFilter::Paeth,
+                    Some("minsum") => RowFilter::MinSum,
+                    Some("entropy") => RowFilter::Entropy,
+                    Some("bigrams") => RowFilter::Bigrams,
+                    Some("bigent") => RowFilter::BigEnt,
+                    Some("brute") => RowFilter::Brute,
+                    _ => panic!(
+                        "Target contains unknown 'output.filters' entry '{}' in {:?}",
+                        filter, manifest_path
+                    ),
+                })
+                .collect(),
+        );
+    }
+
+    for (key, value) in [
+        ("optimize_alpha", &mut oxipng_options.optimize_alpha),
+        (
+            "bit_depth_reduction",
+            &mut oxipng_options.bit_depth_reduction,
+        ),
+        (
+            "color_type_reduction",
+            &mut oxipng_options.color_type_reduction,
+        ),
+    ] {
+        if let Some(option) = output.get(key) {
+            *value = match option.as_bool() {
+                Some(option) => option,
+                None => panic!(
+                    "Target contains invalid 'output.{}' '{}' (must be a boolean) in {:?}",
+                    key, option, manifest_path
+                ),
+            };
+        }
+    }
+
+    if let Some(strip) = output.get("strip") {
+        oxipng_options.strip = match strip.as_str() {
+            Some("none") => StripChunks::None,
+            Some("safe") => StripChunks::Safe,
+            Some("all") => StripChunks::All,
+            _ => panic!("Target contains invalid 'output.strip' '{}' (must be 'none', 'safe' or 'all') in {:?}", strip, manifest_path),
+        };
+    }
+
+    let family = match output.get("family") {
+        Some(family) => match family.as_str() {
+            Some(family) => family.to_string(),
+            None => panic!(
+                "Target contains invalid 'output.family' '{}' in {:?}",
+                family, manifest_path
+            ),
+        },
+        None => name.to_string(),
+    };
+
+    match output.get("format") {
+        Some(format) => match format.as_str().unwrap() {
+            "none" => OutputFormat::None,
+            "svg" => OutputFormat::Svg,
+            "png-image" => OutputFormat::Raster {
+                format: EncodeTarget::PngImage,
+                sizes: sizes.unwrap(),
+            },
+            "png-oxipng-zopfli" => {
+                match compression {
+                    Some(compression) => {
+                        if compression > 14.0 {
+                            panic!("Target uses 'png-oxipng-zopfli', but contains 'output.compression' '{}' (must be 0-14) in {:?}", compression, manifest_path);
+                        }
+
+                        OutputFormat::Raster {
+                            format: EncodeTarget::PngOxipng {
+                                mode: OxiPngMode::Zopfli(compression as u8),
+                                options: oxipng_options,
+                            },
+                            sizes: sizes.unwrap(),
+                        }
+                    },
+                    None => panic!("Target uses 'png-oxipng-zopfli', but doesn't specify 'output.compression' in {:?}", manifest_path),
+                }
+            }
+            "png-oxipng-libdeflater" => {
+                match compression {
+                    Some(compression) => {
+                        if compression > 15.0 {
+                            panic!("Target uses 'png-oxipng-libdeflater', but contains 'output.compression' '{}' (must be 0-12) in {:?}", compression, manifest_path);
+                        }
+
+                        OutputFormat::Raster {
+                            format: EncodeTarget::PngOxipng {
+                                mode: OxiPngMode::Libdeflater(compression as u8),
+                                options: oxipng_options,
+                            },
+                            sizes: sizes.unwrap(),
+                        }
+                    },
+                    None => panic!("Target uses 'png-oxipng-libdeflater', but doesn't specify 'output.compression' in {:?}", manifest_path),
+                }
+            }
+            "png-quantized" => {
+                let compression = match compression {
+                    Some(compression) => {
+                        if compression > 12.0 {
+                            panic!("Target uses 'png-quantized', but contains 'output.compression' '{}' (must be 0-12) in {:?}", compression, manifest_path);
+                        }
+
+                        compression as u8
+                    }
+                    None => 12,
+                };
+
+                OutputFormat::Raster {
+                    format: EncodeTarget::PngQuantized {
+                        colors,
+                        dither,
+                        mode: OxiPngMode::Libdeflater(compression),
+                        options: oxipng_options,
+                    },
+                    sizes: sizes.unwrap(),
+                }
+            }
+            "webp" | "webp-animated" => {
+                let format_name = format.as_str().unwrap();
+
+                let quality = match compression {
+                    Some(compression) => {
+                        if compression > 100.0 {
+                            panic!("Target uses '{}', but contains 'output.compression' '{}' (must be 0.0-100.0) in {:?}", format_name, compression, manifest_path);
+                        }
+
+                        compression as f32
+                    }
+                    None => 80.0,
+                };
+
+                let method = method.unwrap_or(4);
+
+                OutputFormat::Raster {
+                    format: if format_name == "webp" {
+                        EncodeTarget::Webp { lossless, quality, method }
+                    } else {
+                        EncodeTarget::WebpAnimated { lossless, quality, method }
+                    },
+                    sizes: sizes.unwrap(),
+                }
+            }
+            "jxl" => {
+                let effort = effort.unwrap_or(7);
+
+                if lossless && distance.is_some() {
+                    panic!("Target uses 'jxl' with 'output.lossless', but contains 'output.distance' in {:?}", manifest_path);
+                }
+
+                // The encoder drops the alpha channel of lossy images at the lowest efforts
+                if !lossless && effort < 3 {
+                    panic!("Target uses lossy 'jxl', but contains 'output.effort' '{}' (must be 3-10) in {:?}", effort, manifest_path);
+                }
+
+                OutputFormat::Raster {
+                    format: EncodeTarget::Jxl {
+                        lossless,
+                        distance: distance.unwrap_or(1.0),
+                        effort,
+                    },
+                    sizes: sizes.unwrap(),
+                }
+            }
+            "avif-lossy" => {
+                match compression {
+                    Some(compression) => {
+                        if compression > 100.0 || compression < 1.0 {
+                            panic!("Target uses 'avif-lossy', but contains 'output.compression' '{}' (must be 1.0-100.0) in {:?}", compression, manifest_path);
+                        }
+
+                        OutputFormat::Raster {
+                            format: EncodeTarget::Avif(AvifOptions {
+                                lossless: false,
+                                quality: compression as f32,
+                                alpha_quality: alpha_quality.unwrap_or(compression as f32),
+                                speed,
+                                depth,
+                                color_space,
+                                threads,
+                            }),
+                            sizes: sizes.unwrap(),
+                        }
+                    },
+                    None => panic!("Target uses 'avif-lossy', but doesn't specify 'output.compression' in {:?}", manifest_path),
+                }
+            }
+            "avif-lossless" => {
+                // Lossless AVIF is only lossless in RGB
+                if output.contains_key("color_space") {
+                    panic!("Target uses 'avif-lossless', but contains 'output.color_space' (always 'rgb') in {:?}", manifest_path);
+                }
+
+                OutputFormat::Raster {
+                    format: EncodeTarget::Avif(AvifOptions {
+                        lossless: true,
+                        quality: 100.0,
+                        alpha_quality: 100.0,
+                        speed,
+                        depth,
+                        color_space: AvifColorSpace::Rgb,
+                        threads,
+                    }),
+                    sizes: sizes.unwrap(),
+                }
+            }
+            "apng" => OutputFormat::Raster {
+                format: EncodeTarget::Apng,
+                sizes: sizes.unwrap(),
+            },
+            "gif" => {
+                let sizes = sizes.unwrap();
+
+                if let Some(size) = sizes.iter().find(|size| **size > 65535) {
+                    panic!("Target uses 'gif', but contains 'output.size' '{}' (over 65535) in {:?}", size, manifest_path);
+                }
+
+                OutputFormat::Raster {
+                    format: EncodeTarget::Gif,
+                    sizes,
+                }
+            }
+            "auto" => {
+                let candidates = match output.get("candidates") {
+                    Some(candidates) => match candidates.as_array() {
+                        Some(candidates) if !candidates.is_empty() => candidates,
+                        _ => panic!("Target uses 'auto', but contains invalid 'output.candidates' '{}' (must be a non-empty array) in {:?}", candidates, manifest_path),
+                    },
+                    None => panic!("Target uses 'auto', but doesn't specify 'output.candidates' in {:?}", manifest_path),
+                };
+
+                let size = match output.get("size") {
+                    Some(size) => size,
+                    None => panic!("Target uses 'auto', but doesn't specify 'output.size' in {:?}", manifest_path),
+                };
+
+                let candidates = candidates
+                    .iter()
+                    .map(|candidate| {
+                        let mut candidate = match candidate.as_table() {
+                            Some(candidate) => candidate.clone(),
+                            None => panic!("Target contains invalid 'output.candidates' entry '{}' (must be a table) in {:?}", candidate, manifest_path),
+                        };
+
+                        // Candidates are encoded at the sizes of the auto output
+                        if candidate.contains_key("size") {
+                            panic!("Target contains 'output.candidates' entry with 'size', which is set by the auto output in {:?}", manifest_path);
+                        }
+                        candidate.insert("size".to_string(), size.clone());
+
+                        let format_name = match candidate.get("format").and_then(|format| format.as_str()) {
+                            Some(format_name) => format_name.to_string(),
+                            None => panic!("Target contains 'output.candidates' entry without a 'format' in {:?}", manifest_path),
+                        };
+
+                        // Recorded in the metadata as the encoder of the files it was kept for
+                        let candidate_name = match candidate.remove("name") {
+                            Some(Value::String(candidate_name)) => candidate_name,
+                            Some(candidate_name) => panic!("Target contains invalid 'output.candidates' entry name '{}' (must be a string) in {:?}", candidate_name, manifest_path),
+                            None => format_name.clone(),
+                        };
+
+                        match parse_output(&candidate, name, manifest_path) {
+                            OutputFormat::Raster { format, .. } if !format.is_animated() => (candidate_name, format),
+                            _ => panic!("Target contains 'output.candidates' entry '{}', which is not a static raster format in {:?}", candidate_name, manifest_path),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                for (index, (candidate_name, _)) in candidates.iter().enumerate() {
+                    if candidates[..index].iter().any(|(other, _)| other == candidate_name) {
+                        panic!("Target contains several 'output.candidates' entries named '{}' (set a different 'name' on each) in {:?}", candidate_name, manifest_path);
+                    }
+                }
+
+                let min_psnr = match output.get("min_psnr") {
+                    Some(min_psnr) => match min_psnr.as_float() {
+                        Some(min_psnr) if min_psnr > 0.0 => Some(min_psnr),
+                        _ => panic!("Target contains invalid 'output.min_psnr' '{}' (must be a positive decimal) in {:?}", min_psnr, manifest_path),
+                    },
+                    None => None,
+                };
+
+                // Lossy candidates are decoded to measure their quality
+                if min_psnr.is_some() {
+                    for (candidate_name, format) in &candidates {
+                        if !format.is_lossless() && !format.can_decode() {
+                            panic!("Target uses 'auto' with 'output.min_psnr', but candidate '{}' can't be decoded to measure its quality in {:?}", candidate_name, manifest_path);
+                        }
+                    }
+                }
+
+                OutputFormat::Auto {
+                    candidates,
+                    min_psnr,
+                    sizes: sizes.unwrap(),
+                }
+            }
+            "font-colrv1" => OutputFormat::Font {
+                format: FontFormat::Colrv1,
+                family,
+            },
+            "font-svg" => OutputFormat::Font {
+                format: FontFormat::Svg,
+                family,
+            },
+            "font-cbdt" => {
+                let sizes = match sizes {
+                    Some(sizes) => sizes,
+                    None => panic!("Target uses 'font-cbdt', but doesn't specify 'output.size' in {:?}", manifest_path),
+                };
+
+                for size in &sizes {
+                    if *size < 1 || *size > 128 {
+                        panic!("Target uses 'font-cbdt', but contains 'output.size' '{}' (must be 1-128) in {:?}", size, manifest_path);
+                    }
+                }
+
+                let compression = match compression {
+                    Some(compression) => {
+                        if compression > 12.0 {
+                            panic!("Target uses 'font-cbdt', but contains 'output.compression' '{}' (must be 0-12) in {:?}", compression, manifest_path);
+                        }
+
+                        compression as u8
+                    }
+                    None => 12,
+                };
+
+                OutputFormat::Font {
+                    format: FontFormat::Cbdt {
+                        sizes,
+                        encoding: EncodeTarget::PngOxipng {
+                            mode: OxiPngMode::Libdeflater(compression),
+                            options: oxipng_options,
+                        },
+                    },
+                    family,
+                }
+            }
+            _ => panic!("Target contains unknown 'output.format' '{}' in {:?}", format, manifest_path),
+        },
+        None => panic!("Target is missing 'output.format' in {:?}", manifest_path),
+    }
 }
 
 impl Emoji {
@@ -134,12 +1052,69 @@ impl Pack {
     }
 
     pub fn load_manifests(&mut self, index_path: &PathBuf) {
-        // Starting with the index
-        let mut queue = vec![index_path.clone()];
-        // Load manifest files recursively
-        while let Some(manifest_path) = queue.pop() {
+        // Starting with the index, along with the manifest including each manifest
+        let mut queue: Vec<(PathBuf, Option<usize>)> = vec![(index_path.clone(), None)];
+        let mut manifests = Vec::new();
+        let mut parents: Vec<Option<usize>> = Vec::new();
+        // The definitions of each manifest, and whether they are exported
+        let mut manifest_definitions: Vec<Vec<(String, Definition, bool)>> = Vec::new();
+        // Load manifest files recursively, collecting definitions first so that every
+        // manifest can use them
+        while let Some((manifest_path, parent)) = queue.pop() {
+            let index = manifests.len();
             let manifest = Self::load_manifest(&manifest_path);
 
+            // Define
+            let mut own_definitions = Vec::new();
+            if let Some(defines) = manifest.get("define") {
+                let defines = defines.as_array().unwrap();
+
+                for define in defines.iter() {
+                    let define = define.as_table().unwrap();
+                    let export = parse_export(define, "Define", &manifest_path);
+
+                    for (key, value) in define.iter() {
+                        if key == "export" {
+                            continue;
+                        }
+
+                        if !key.starts_with('$') {
+                            panic!(
+                                "Define name '{}' does not start with '$' in {:?}",
+                                key, manifest_path
+                            );
+                        }
+
+                        let value = match value.as_str() {
+                            Some(value) => value,
+                            None => panic!("Define value is not a string in {:?}", manifest_path),
+                        };
+
+                        own_definitions.push((
+                            key.to_string(),
+                            Definition {
+                                value: value.to_string(),
+                                manifest_path: manifest_path.clone(),
+                                scope: index,
+                            },
+                            export,
+                        ));
+                    }
+                }
+            }
+
+            // Include paths can use the definitions of this manifest and the manifests including it
+            let mut include_scope = match parent {
+                Some(parent) => self.definitions[parent].clone(),
+                None => HashMap::new(),
+            };
+            for (name, definition, _) in &own_definitions {
+                include_scope.insert(name.clone(), definition.clone());
+            }
+            self.definitions.push(include_scope);
+            manifest_definitions.push(own_definitions);
+            parents.push(parent);
+
             // Include
             // If this manifest contains include entries
             if let Some(inclusions) = manifest.get("include") {
@@ -147,14 +1122,19 @@ impl Pack {
                 let includes = inclusions.as_array().unwrap();
 
                 // Flatten and collect the paths
-                let mut relative_paths: Vec<&str> = Vec::new();
+                let mut relative_paths: Vec<String> = Vec::new();
 
                 for include in includes.iter() {
                     if let Some(paths) = include.get("paths") {
                         let paths = paths.as_array().unwrap();
 
                         for path in paths.iter() {
-                            relative_paths.push(path.as_str().unwrap());
+                            relative_paths.push(variable::interpolate(
+                                path.as_str().unwrap(),
+                                &self.definitions,
+                                index,
+                                &manifest_path,
+                            ));
                         }
                     }
                 }
@@ -174,25 +1154,41 @@ impl Pack {
                         ),
                     };
 
-                    queue.push(new_path);
+                    queue.push((new_path, Some(index)));
                 }
             }
 
-            // Define
-            if let Some(definitions) = manifest.get("define") {
-                let definitions = definitions.as_array().unwrap();
-
-                for definition in definitions.iter() {
-                    let definition = definition.as_table().unwrap();
-
-                    for (key, value) in definition.iter() {
-                        let value = match value.as_str() {
-                            Some(value) => value,
-                            None => panic!("Define value is not a string in {:?}", manifest_path),
-                        };
+            manifests.push((manifest_path, manifest));
+        }
 
-                        self.definitions.insert(key.to_string(), value.to_string());
-                    }
+        let mut warnings = Vec::new();
+
+        self.definitions = scope_entries(
+            &parents,
+            &manifest_definitions,
+            |definition| definition.manifest_path.clone(),
+            "Variable",
+            &mut warnings,
+        );
+        variable::check_definitions(&self.definitions);
+
+        // The colormaps of each manifest, and whether they are exported
+        let mut manifest_colormaps: Vec<Vec<(String, usize, bool)>> =
+            vec![Vec::new(); manifests.len()];
+        // The ramps of each manifest, and whether they are exported
+        let mut ramps: Vec<(HashMap<String, f64>, PathBuf)> = Vec::new();
+        let mut manifest_ramp_names: Vec<Vec<(String, usize, bool)>> =
+            vec![Vec::new(); manifests.len()];
+        // The templates of each manifest, and whether they are exported
+        let mut templates: Vec<Template> = Vec::new();
+        let mut manifest_template_names: Vec<Vec<(String, usize, bool)>> =
+            vec![Vec::new(); manifests.len()];
+
+        for (index, (manifest_path, manifest)) in manifests.iter_mut().enumerate() {
+            // Interpolate variables everywhere else
+            for (key, value) in manifest.as_table_mut().unwrap().iter_mut() {
+                if key != "define" && key != "include" {
+                    variable::interpolate_value(value, &self.definitions, index, manifest_path);
                 }
             }
 
@@ -215,12 +1211,14 @@ impl Pack {
                     let mut shortcode: Option<String> = None;
                     let mut codepoint: Option<Vec<String>> = None;
                     let mut description: Option<String> = None;
+                    let mut base: Option<String> = None;
+                    let mut ramp: Option<Ramp> = None;
 
                     let mut entries = HashMap::new();
 
                     for (key, value) in colormap.iter() {
                         match key.as_str() {
-                            "name" => (),
+                            "name" | "export" => (),
                             "label" => {
                                 label = match value.as_str() {
                                     Some(label) => Some(label.to_string()),
@@ -244,9 +1242,9 @@ impl Pack {
                                     Some(codepoint) => {
                                         let codepoint = codepoint
                                             .iter()
-                                            .map(|codepoint| {
+                                            .flat_map(|codepoint| {
                                                 match codepoint.as_str() {
-                                                    Some(codepoint) => codepoint.to_string(),
+                                                    Some(codepoint) => split_components(codepoint),
                                                     None => panic!(
                                                         "Colormap codepoint component is not a string in {:?}",
                                                         manifest_path
@@ -272,39 +1270,267 @@ impl Pack {
                                     ),
                                 }
                             }
-                            _ => {
-                                let value = match value.as_str() {
-                                    Some(value) => value,
+                            "base" => {
+                                base = match value.as_str() {
+                                    Some(base) => Some(base.to_string()),
                                     None => panic!(
-                                        "Colormap entry value is not a string in {:?}",
+                                        "Colormap base is not a string in {:?}",
+                                        manifest_path
+                                    ),
+                                }
+                            }
+                            "ramp" => {
+                                ramp = match value {
+                                    Value::String(ramp) => Some(Ramp::Named(ramp.to_string())),
+                                    Value::Table(shades) => Some(Ramp::Shades(parse_shades(
+                                        shades.iter(),
+                                        &name,
+                                        manifest_path,
+                                    ))),
+                                    _ => panic!(
+                                        "Colormap ramp is not a string or table in {:?}",
+                                        manifest_path
+                                    ),
+                                }
+                            }
+                            _ => {
+                                let entry = match value {
+                                    Value::String(color) => ColormapEntry {
+                                        color: color.to_string(),
+                                        opacity: None,
+                                        properties: None,
+                                    },
+                                    Value::Table(entry) => {
+                                        parse_colormap_entry(entry, &name, key, manifest_path)
+                                    }
+                                    _ => panic!(
+                                        "Colormap entry value is not a string or table in {:?}",
                                         manifest_path
                                     ),
                                 };
-                                entries.insert(key.to_string(), value.to_string());
+                                entries.insert(key.to_string(), entry);
                             }
                         }
                     }
 
-                    self.colormaps.insert(
+                    if base.is_some() != ramp.is_some() {
+                        panic!(
+                            "Colormap '{}' must specify both 'base' and 'ramp', or neither in {:?}",
+                            name, manifest_path
+                        );
+                    }
+
+                    manifest_colormaps[index].push((
+                        name.clone(),
+                        self.colormaps.len(),
+                        parse_export(colormap, "Colormap", manifest_path),
+                    ));
+                    self.colormaps.push(Colormap {
                         name,
-                        Colormap {
-                            label,
-                            shortcode,
-                            codepoint,
-                            description,
-                            entries,
+                        manifest_path: manifest_path.clone(),
+                        scope: index,
+                        label,
+                        shortcode,
+                        codepoint,
+                        description,
+                        entries,
+                        base,
+                        ramp,
+                    });
+                }
+            }
+
+            // Ramp
+            if let Some(manifest_ramps) = manifest.get("ramp") {
+                let manifest_ramps = manifest_ramps.as_array().unwrap();
+
+                for ramp in manifest_ramps.iter() {
+                    let ramp = ramp.as_table().unwrap();
+
+                    let name = match ramp.get("name") {
+                        Some(name) => match name.as_str() {
+                            Some(name) => name.to_string(),
+                            None => panic!("Ramp name is not a string in {:?}", manifest_path),
                         },
+                        None => panic!("Ramp is missing 'name' in {:?}", manifest_path),
+                    };
+
+                    let shades = parse_shades(
+                        ramp.iter()
+                            .filter(|(key, _)| *key != "name" && *key != "export"),
+                        &name,
+                        manifest_path,
                     );
+
+                    manifest_ramp_names[index].push((
+                        name,
+                        ramps.len(),
+                        parse_export(ramp, "Ramp", manifest_path),
+                    ));
+                    ramps.push((shades, manifest_path.clone()));
+                }
+            }
+
+            // Template
+            if let Some(manifest_templates) = manifest.get("template") {
+                let manifest_templates = manifest_templates.as_array().unwrap();
+
+                for template in manifest_templates.iter() {
+                    let mut template = match template.as_table() {
+                        Some(template) => template.clone(),
+                        None => panic!("Template is not a table in {:?}", manifest_path),
+                    };
+
+                    let name = match template.remove("name") {
+                        Some(Value::String(name)) => name,
+                        Some(_) => panic!("Template name is not a string in {:?}", manifest_path),
+                        None => panic!("Template is missing 'name' in {:?}", manifest_path),
+                    };
+
+                    // Paths are relative to the manifest of the template, not of the emoji
+                    let mut directory = manifest_path.clone();
+                    directory.pop();
+
+                    let absolute = |src: &mut Value| {
+                        if let Value::String(src) = src {
+                            *src = directory.join(&*src).display().to_string();
+                        }
+                    };
+
+                    if let Some(src) = template.get_mut("src") {
+                        absolute(src);
+                    }
+
+                    if let Some(Value::Array(frames)) = template.get_mut("frames") {
+                        for frame in frames.iter_mut() {
+                            if let Some(src) = frame.get_mut("src") {
+                                absolute(src);
+                            }
+                        }
+                    }
+
+                    let export = parse_export(&template, "Template", manifest_path);
+                    template.remove("export");
+
+                    manifest_template_names[index].push((name.clone(), templates.len(), export));
+                    templates.push(Template {
+                        name,
+                        table: template,
+                        scope: index,
+                        manifest_path: manifest_path.clone(),
+                    });
                 }
             }
+        }
 
+        let colormap_scopes = scope_entries(
+            &parents,
+            &manifest_colormaps,
+            |colormap| self.colormaps[*colormap].manifest_path.clone(),
+            "Colormap",
+            &mut warnings,
+        );
+
+        self.ramps = scope_entries(
+            &parents,
+            &manifest_ramp_names,
+            |ramp| ramps[*ramp].1.clone(),
+            "Ramp",
+            &mut warnings,
+        )
+        .into_iter()
+        .map(|scope| {
+            scope
+                .into_iter()
+                .map(|(name, ramp)| (name, ramps[ramp].0.clone()))
+                .collect()
+        })
+        .collect();
+
+        let template_scopes = scope_entries(
+            &parents,
+            &manifest_template_names,
+            |template| templates[*template].manifest_path.clone(),
+            "Template",
+            &mut warnings,
+        );
+
+        for warning in warnings {
+            self.logger.warn(&warning);
+        }
+
+        for (index, (manifest_path, manifest)) in manifests.iter().enumerate() {
             // Emoji
             if let Some(emojis) = manifest.get("emoji") {
                 let emojis = emojis.as_array().unwrap();
 
                 for emoji in emojis.iter() {
-                    let emoji = emoji.as_table().unwrap();
+                    let emoji = &extend_template(
+                        emoji.as_table().unwrap(),
+                        index,
+                        &templates,
+                        &template_scopes,
+                        manifest_path,
+                        &mut Vec::new(),
+                    );
+
+                    let frames = match emoji.get("frames") {
+                        Some(frames) => {
+                            let frames = match frames.as_array() {
+                                Some(frames) => frames,
+                                None => {
+                                    panic!("Emoji 'frames' is not an array in {:?}", manifest_path)
+                                }
+                            };
+
+                            if frames.is_empty() {
+                                panic!("Emoji 'frames' is empty in {:?}", manifest_path);
+                            }
+
+                            Some(
+                                frames
+                                    .iter()
+                                    .map(|frame| {
+                                        let frame = match frame.as_table() {
+                                            Some(frame) => frame,
+                                            None => panic!("Emoji frame is not a table in {:?}", manifest_path),
+                                        };
+
+                                        let src = match frame.get("src") {
+                                            Some(src) => {
+                                                let mut full_path = manifest_path.clone();
+                                                full_path.pop();
+
+                                                full_path.push(match src.as_str() {
+                                                    Some(src) => src,
+                                                    None => panic!("Emoji frame 'src' is not a string in {:?}", manifest_path),
+                                                });
+
+                                                match full_path.canonicalize() {
+                                                    Ok(full_path) => full_path,
+                                                    Err(err) => panic!("Could not find emoji frame src file '{:?}' with error '{}' in '{:?}'", full_path, err, manifest_path),
+                                                }
+                                            }
+                                            None => panic!("Emoji frame is missing 'src' in {:?}", manifest_path),
+                                        };
+
+                                        let duration = match frame.get("duration") {
+                                            Some(duration) => match duration.as_integer() {
+                                                Some(duration) if (1..=65535).contains(&duration) => duration as u32,
+                                                _ => panic!("Emoji frame 'duration' must be an integer between 1 and 65535 (milliseconds) in {:?}", manifest_path),
+                                            },
+                                            None => panic!("Emoji frame is missing 'duration' in {:?}", manifest_path),
+                                        };
 
+                                        (src, duration)
+                                    })
+                                    .collect::<Vec<_>>(),
+                            )
+                        }
+                        None => None,
+                    };
+
+                    // Frames stand in for the source, which defaults to the first frame
                     let src = match emoji.get("src") {
                         Some(src) => {
                             let mut full_path = manifest_path.clone();
@@ -320,7 +1546,51 @@ impl Pack {
                                 Err(err) => panic!("Could not find emoji src file '{:?}' with error '{}' in '{:?}'", full_path, err, manifest_path),
                             }
                         }
-                        None => panic!("Emoji is missing 'src' in {:?}", manifest_path),
+                        None => match &frames {
+                            Some(frames) => frames[0].0.clone(),
+                            None => panic!("Emoji is missing 'src' in {:?}", manifest_path),
+                        },
+                    };
+
+                    let animation = match emoji.get("animation") {
+                        Some(animation) => {
+                            if frames.is_some() {
+                                panic!(
+                                    "Emoji cannot have both 'frames' and 'animation' in {:?}",
+                                    manifest_path
+                                );
+                            }
+
+                            let animation = match animation.as_table() {
+                                Some(animation) => animation,
+                                None => panic!(
+                                    "Emoji 'animation' is not a table in {:?}",
+                                    manifest_path
+                                ),
+                            };
+
+                            let frame_rate = match animation.get("frame_rate") {
+                                Some(frame_rate) => match frame_rate.as_integer() {
+                                    Some(frame_rate) if (1..=100).contains(&frame_rate) => frame_rate as u32,
+                                    _ => panic!("Emoji animation 'frame_rate' must be an integer between 1 and 100 in {:?}", manifest_path),
+                                },
+                                None => panic!("Emoji 'animation' is missing 'frame_rate' in {:?}", manifest_path),
+                            };
+
+                            let duration = match animation.get("duration") {
+                                Some(duration) => match duration.as_integer() {
+                                    Some(duration) if (1..=600000).contains(&duration) => Some(duration as u32),
+                                    _ => panic!("Emoji animation 'duration' must be an integer between 1 and 600000 (milliseconds) in {:?}", manifest_path),
+                                },
+                                None => None,
+                            };
+
+                            Some(Animation::Smil {
+                                frame_rate,
+                                duration,
+                            })
+                        }
+                        None => frames.map(Animation::Frames),
                     };
 
                     let name = match emoji.get("name") {
@@ -378,8 +1648,8 @@ impl Pack {
                                     manifest_path
                                 ))
                                 .iter()
-                                .map(|c| match c.as_str() {
-                                    Some(c) => c.to_string(),
+                                .flat_map(|c| match c.as_str() {
+                                    Some(c) => split_components(c),
                                     None => panic!(
                                         "Emoji 'codepoint' component is not a string in {:?}",
                                         manifest_path
@@ -399,8 +1669,8 @@ impl Pack {
                                     manifest_path
                                 ))
                                 .iter()
-                                .map(|c| match c.as_str() {
-                                    Some(c) => c.to_string(),
+                                .flat_map(|c| match c.as_str() {
+                                    Some(c) => split_components(c),
                                     None => panic!(
                                         "Emoji 'root_codepoint' component is not a string in {:?}",
                                         manifest_path
@@ -429,30 +1699,76 @@ impl Pack {
                         None => vec![],
                     };
 
-                    let colormaps: Vec<String> = match emoji.get("colormaps") {
-                        Some(colormaps) => colormaps
-                            .as_array()
-                            .expect(&format!(
-                                "Emoji 'colormaps' is not an array in {:?}",
-                                manifest_path
-                            ))
+                    let colormap_indices = |colormap: &Value| match colormap.as_str() {
+                        Some(colormap) => split_components(colormap)
                             .iter()
-                            .map(|c| match c.as_str() {
-                                Some(c) => c.to_string(),
-                                None => {
-                                    panic!(
-                                        "Emoji 'colormap' is not a string in {:?}",
-                                        manifest_path
-                                    )
-                                }
+                            .map(|colormap| match colormap_scopes[index].get(colormap) {
+                                Some(colormap) => *colormap,
+                                None => panic!(
+                                    "Emoji '{}' uses colormap '{}' which is undefined in {:?}",
+                                    name, colormap, manifest_path
+                                ),
                             })
-                            .collect(),
+                            .collect::<Vec<_>>(),
+                        None => {
+                            panic!("Emoji 'colormap' is not a string in {:?}", manifest_path)
+                        }
+                    };
+
+                    // A list of colormaps, or a list of axes
+                    let colormaps: Vec<Vec<usize>> = match emoji.get("colormaps") {
+                        Some(colormaps) => {
+                            let colormaps = colormaps.as_array().expect(&format!(
+                                "Emoji 'colormaps' is not an array in {:?}",
+                                manifest_path
+                            ));
+
+                            if colormaps.is_empty() {
+                                vec![]
+                            } else if colormaps.iter().all(|axis| axis.is_array()) {
+                                colormaps
+                                    .iter()
+                                    .map(|axis| {
+                                        axis.as_array()
+                                            .unwrap()
+                                            .iter()
+                                            .flat_map(colormap_indices)
+                                            .collect()
+                                    })
+                                    .collect()
+                            } else {
+                                vec![colormaps.iter().flat_map(colormap_indices).collect()]
+                            }
+                        }
+                        None => vec![],
+                    };
+
+                    let palettes: Vec<HashMap<String, String>> = match emoji.get("palettes") {
+                        Some(palettes) => match palettes.as_array() {
+                            Some(palettes) if palettes.len() <= colormaps.len() => palettes
+                                .iter()
+                                .map(|palette| match palette.as_table() {
+                                    Some(palette) => palette
+                                        .iter()
+                                        .map(|(key, value)| match value.as_str() {
+                                            Some(value) => (key.to_string(), value.to_string()),
+                                            None => panic!("Emoji 'palettes' entry '{}' is not a string in {:?}", key, manifest_path),
+                                        })
+                                        .collect(),
+                                    None => panic!("Emoji 'palettes' entry is not a table in {:?}", manifest_path),
+                                })
+                                .collect(),
+                            _ => panic!("Emoji contains invalid 'palettes' (must be an array of tables, at most one per colormap axis) in {:?}", manifest_path),
+                        },
                         None => vec![],
                     };
 
                     self.emojis.push(Emoji {
                         src,
+                        manifest_path: manifest_path.clone(),
                         svg: None,
+                        animation,
+                        frames: Vec::new(),
                         name,
                         description,
                         category,
@@ -461,6 +1777,7 @@ impl Pack {
                         root_codepoint,
                         shortcodes,
                         colormaps,
+                        palettes,
                     });
                 }
             }
@@ -562,117 +1879,263 @@ impl Pack {
                                 ),
                             };
 
+                            let sizes = match structure.get("sizes") {
+                                Some(sizes) => match sizes.as_str() {
+                                    Some("directory") => SizeNaming::Directory,
+                                    Some("suffix") => SizeNaming::Suffix,
+                                    _ => panic!(
+                                        "Target contains unknown 'structure.sizes' '{}' in {:?}",
+                                        sizes, manifest_path
+                                    ),
+                                },
+                                None => SizeNaming::Directory,
+                            };
+
                             OutputStructure {
                                 container,
                                 filenames,
                                 flat,
+                                sizes,
                             }
                         }
                         None => panic!("Target is missing 'structure' in {:?}", manifest_path),
                     };
 
-                    let output_format = match target.get("output") {
-                        Some(output) => {
-                            let output = output.as_table().unwrap();
+                    let outputs = match target.get("output") {
+                        Some(output) => match output.as_array() {
+                            Some(outputs) => outputs.iter().collect::<Vec<_>>(),
+                            None => vec![output],
+                        },
+                        None => panic!("Target is missing 'output' in {:?}", manifest_path),
+                    };
 
-                            let size = match output.get("size") {
-                                Some(size) => match size.as_integer() {
-                                    Some(size) => {
-                                        if size < 0 {
-                                            panic!("Target contains 'output.size' '{}' (under 0) in {:?}", size, manifest_path);
-                                        }
+                    if outputs.is_empty() {
+                        panic!(
+                            "Target '{}' contains empty 'output' in {:?}",
+                            name, manifest_path
+                        );
+                    }
 
-                                        if size > 65536 {
-                                            panic!("Target contains 'output.size' '{}' (over 65536) in {:?}", size, manifest_path);
-                                        }
+                    let output_formats = outputs
+                        .iter()
+                        .map(|output| {
+                            parse_output(output.as_table().unwrap(), &name, manifest_path)
+                        })
+                        .collect::<Vec<_>>();
+
+                    // Files of different formats are written side by side
+                    let mut extensions = Vec::new();
+                    let mut fonts = Vec::new();
+                    for output_format in &output_formats {
+                        match output_format {
+                            OutputFormat::Svg | OutputFormat::Raster { .. } => {
+                                let extension = match output_format {
+                                    OutputFormat::Raster { format, .. } => format.to_extension(),
+                                    _ => "svg",
+                                };
 
-                                        Some(size as u32)
+                                if extensions.contains(&extension) {
+                                    panic!("Target '{}' contains several output formats with the extension '{}' in {:?}", name, extension, manifest_path);
+                                }
+                                extensions.push(extension);
+                            }
+                            OutputFormat::Auto { candidates, .. } => {
+                                let mut candidate_extensions = candidates
+                                    .iter()
+                                    .map(|(_, format)| format.to_extension())
+                                    .collect::<Vec<_>>();
+                                candidate_extensions.sort();
+                                candidate_extensions.dedup();
+
+                                for extension in candidate_extensions {
+                                    if extensions.contains(&extension) {
+                                        panic!("Target '{}' contains several output formats with the extension '{}' in {:?}", name, extension, manifest_path);
                                     }
-                                    None => panic!(
-                                        "Target contains invalid 'output.size' '{}' in {:?}",
-                                        size, manifest_path
+                                    extensions.push(extension);
+                                }
+                            }
+                            OutputFormat::Font { format, .. } => {
+                                if fonts.contains(&format.to_suffix()) {
+                                    panic!(
+                                        "Target '{}' contains several '{}' fonts in {:?}",
+                                        name,
+                                        format.to_suffix(),
+                                        manifest_path
+                                    );
+                                }
+                                fonts.push(format.to_suffix());
+                            }
+                            OutputFormat::None => {}
+                        }
+                    }
+
+                    let render = match target.get("render") {
+                        Some(render) => {
+                            let render = match render.as_table() {
+                                Some(render) => render,
+                                None => panic!(
+                                    "Target '{}' contains invalid 'render' in {:?}",
+                                    name, manifest_path
+                                ),
+                            };
+
+                            let aspect = match render.get("aspect") {
+                                Some(aspect) => match aspect.as_str() {
+                                    Some("stretch") => AspectMode::Stretch,
+                                    Some("preserve") => AspectMode::Preserve,
+                                    _ => panic!(
+                                        "Target '{}' contains unknown 'render.aspect' '{}' in {:?}",
+                                        name, aspect, manifest_path
                                     ),
                                 },
-                                None => None,
+                                None => AspectMode::Stretch,
                             };
 
-                            let compression = match output.get("compression") {
-                                Some(compression) => match compression.as_float() {
-                                    Some(compression) => {
-                                        if compression < 0.0 {
-                                            panic!("Target contains 'output.compression' '{}' (under 0.0) in {:?}", compression, manifest_path);
+                            let padding = match render.get("padding") {
+                                Some(padding) => match padding.as_float() {
+                                    Some(padding) => {
+                                        if !(0.0..0.5).contains(&padding) {
+                                            panic!("Target '{}' contains 'render.padding' '{}' (must be 0.0-0.5) in {:?}", name, padding, manifest_path);
                                         }
 
-                                        Some(compression)
-                                    },
-                                    None => panic!("Target contains invalid 'output.compression' '{}' (must contain a decimal point) in {:?}", compression, manifest_path),
+                                        padding
+                                    }
+                                    None => panic!("Target '{}' contains invalid 'render.padding' '{}' (must contain a decimal point) in {:?}", name, padding, manifest_path),
                                 },
-                                None => None,
+                                None => 0.0,
                             };
 
-                            match output.get("format") {
-                                Some(format) => match format.as_str().unwrap() {
-                                    "none" => OutputFormat::None,
-                                    "svg" => OutputFormat::Svg,
-                                    "png-image" => OutputFormat::Raster {
-                                        format: EncodeTarget::PngImage,
-                                        size: size.unwrap() as u32,
-                                    },
-                                    "png-oxipng-zopfli" => {
-                                        match compression {
-                                            Some(compression) => {
-                                                if compression > 14.0 {
-                                                    panic!("Target uses 'png-oxipng-zopfli', but contains 'output.compression' '{}' (must be 0-14) in {:?}", compression, manifest_path);
-                                                }
+                            let [width, height] = ["width", "height"].map(|key| match render.get(key) {
+                                Some(value) => match value.as_integer() {
+                                    Some(value) if (1..=65536).contains(&value) => Some(value as u32),
+                                    _ => panic!("Target '{}' contains invalid 'render.{}' '{}' (must be 1-65536) in {:?}", name, key, value, manifest_path),
+                                },
+                                None => None,
+                            });
 
-                                                OutputFormat::Raster {
-                                                    format: EncodeTarget::PngOxipng(OxiPngMode::Zopfli(compression as u8)),
-                                                    size: size.unwrap() as u32,
-                                                }
-                                            },
-                                            None => panic!("Target uses 'png-oxipng-zopfli', but doesn't specify 'output.compression' in {:?}", manifest_path),
+                            if width.is_some() || height.is_some() {
+                                for output_format in &output_formats {
+                                    if let OutputFormat::Raster { sizes, .. }
+                                    | OutputFormat::Auto { sizes, .. } = output_format
+                                    {
+                                        if sizes.len() > 1 {
+                                            panic!("Target '{}' sets 'render.width' or 'render.height', but has several sizes in {:?}", name, manifest_path);
                                         }
                                     }
-                                    "png-oxipng-libdeflater" => {
-                                        match compression {
-                                            Some(compression) => {
-                                                if compression > 15.0 {
-                                                    panic!("Target uses 'png-oxipng-libdeflater', but contains 'output.compression' '{}' (must be 0-12) in {:?}", compression, manifest_path);
-                                                }
+                                }
+                            }
 
-                                                OutputFormat::Raster {
-                                                    format: EncodeTarget::PngOxipng(OxiPngMode::Libdeflater(compression as u8)),
-                                                    size: size.unwrap() as u32,
-                                                }
-                                            },
-                                            None => panic!("Target uses 'png-oxipng-libdeflater', but doesn't specify 'output.compression' in {:?}", manifest_path),
-                                        }
-                                    }
-                                    "webp" => OutputFormat::Raster {
-                                        format: EncodeTarget::Webp,
-                                        size: size.unwrap() as u32,
-                                    },
-                                    "avif-lossy" => {
-                                        match compression {
-                                            Some(compression) => {
-                                                if compression > 100.0 || compression < 1.0 {
-                                                    panic!("Target uses 'avif-lossy', but contains 'output.compression' '{}' (must be 1.0-100.0) in {:?}", compression, manifest_path);
-                                                }
+                            let background = match render.get("background") {
+                                Some(background) => match background.as_str().and_then(parse_color) {
+                                    Some(background) => Some(background),
+                                    None => panic!("Target '{}' contains invalid 'render.background' '{}' (must be a hex, rgb(), hsl() or named color) in {:?}", name, background, manifest_path),
+                                },
+                                None => None,
+                            };
 
-                                                OutputFormat::Raster {
-                                                    format: EncodeTarget::Avif { quality: compression as f32, speed: 1 },
-                                                    size: size.unwrap() as u32,
-                                                }
-                                            },
-                                            None => panic!("Target uses 'avif-lossy', but doesn't specify 'output.compression' in {:?}", manifest_path),
-                                        }
-                                    }
-                                    _ => panic!("Target contains unknown 'output.format' '{}' in {:?}", format, manifest_path),
+                            let supersample = match render.get("supersample") {
+                                Some(supersample) => match supersample.as_integer() {
+                                    Some(supersample) if (1..=16).contains(&supersample) => supersample as u32,
+                                    _ => panic!("Target '{}' contains invalid 'render.supersample' '{}' (must be 1-16) in {:?}", name, supersample, manifest_path),
+                                },
+                                None => 1,
+                            };
+
+                            let filter = match render.get("filter") {
+                                Some(filter) => match filter.as_str() {
+                                    Some("box") => DownscaleFilter::Box,
+                                    Some("mitchell") => DownscaleFilter::Mitchell,
+                                    Some("lanczos") => DownscaleFilter::Lanczos,
+                                    _ => panic!(
+                                        "Target '{}' contains unknown 'render.filter' '{}' in {:?}",
+                                        name, filter, manifest_path
+                                    ),
+                                },
+                                None => DownscaleFilter::Lanczos,
+                            };
+
+                            let snap = match render.get("snap") {
+                                Some(snap) => match snap.as_bool() {
+                                    Some(snap) => snap,
+                                    None => panic!(
+                                        "Target '{}' contains invalid 'render.snap' '{}' in {:?}",
+                                        name, snap, manifest_path
+                                    ),
                                 },
-                                None => panic!("Target is missing 'output.format' in {:?}", manifest_path),
+                                None => false,
+                            };
+
+                            RenderOptions {
+                                aspect,
+                                padding,
+                                width,
+                                height,
+                                background,
+                                supersample,
+                                filter,
+                                snap,
                             }
                         }
-                        None => panic!("Target is missing 'format' in {:?}", manifest_path),
+                        None => RenderOptions::default(),
+                    };
+
+                    let effects = match target.get("effects") {
+                        Some(effects) => match effects.as_array() {
+                            Some(effects) => effects
+                                .iter()
+                                .map(|effect| {
+                                    let effect = match effect.as_table() {
+                                        Some(effect) => effect,
+                                        None => panic!("Target '{}' contains invalid effect '{}' in {:?}", name, effect, manifest_path),
+                                    };
+
+                                    let length = |key: &str, default: Option<f64>| match effect.get(key) {
+                                        Some(value) => match value.as_float() {
+                                            Some(value) if (0.0..0.5).contains(&value) => value,
+                                            _ => panic!("Target '{}' contains invalid effect '{}' '{}' (must be 0.0-0.5) in {:?}", name, key, value, manifest_path),
+                                        },
+                                        None => match default {
+                                            Some(default) => default,
+                                            None => panic!("Target '{}' contains an effect without '{}' in {:?}", name, key, manifest_path),
+                                        },
+                                    };
+
+                                    let color = |default: [u8; 4]| match effect.get("color") {
+                                        Some(color) => match color.as_str().and_then(parse_color) {
+                                            Some(color) => color,
+                                            None => panic!("Target '{}' contains invalid effect 'color' '{}' (must be a hex, rgb(), hsl() or named color) in {:?}", name, color, manifest_path),
+                                        },
+                                        None => default,
+                                    };
+
+                                    match effect.get("type").and_then(|kind| kind.as_str()) {
+                                        Some("padding") => Effect::Padding(length("amount", None)),
+                                        Some("outline") => Effect::Outline {
+                                            width: length("width", None),
+                                            color: color([255, 255, 255, 255]),
+                                        },
+                                        Some("shadow") => {
+                                            let offset = match effect.get("offset") {
+                                                Some(offset) => match offset.as_array().map(|offset| offset.iter().map(|value| value.as_float()).collect::<Option<Vec<_>>>()) {
+                                                    Some(Some(offset)) if offset.len() == 2 => (offset[0], offset[1]),
+                                                    _ => panic!("Target '{}' contains invalid effect 'offset' '{}' (must be [x, y]) in {:?}", name, offset, manifest_path),
+                                                },
+                                                None => (0.0, 0.0),
+                                            };
+
+                                            Effect::Shadow {
+                                                offset,
+                                                blur: length("blur", Some(0.0)),
+                                                color: color([0, 0, 0, 128]),
+                                            }
+                                        }
+                                        _ => panic!("Target '{}' contains an effect with unknown 'type' in {:?}", name, manifest_path),
+                                    }
+                                })
+                                .collect(),
+                            None => panic!("Target '{}' contains invalid 'effects' in {:?}", name, manifest_path),
+                        },
+                        None => vec![],
                     };
 
                     let include_files = match target.get("include_files") {
@@ -708,7 +2171,9 @@ impl Pack {
                         tags,
                         include_tags,
                         output_structure,
-                        output_format,
+                        output_formats,
+                        render,
+                        effects,
                         include_files,
                     });
                 }
@@ -716,3 +2181,155 @@ impl Pack {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shades() {
+        assert_eq!(parse_shade("base"), Some(0.0));
+        assert_eq!(parse_shade(" base "), Some(0.0));
+        assert_eq!(parse_shade("lighten 10%"), Some(0.1));
+        assert_eq!(parse_shade("darken 12.5%"), Some(-0.125));
+        assert_eq!(parse_shade("darken 22 %"), Some(-0.22));
+        assert_eq!(parse_shade("lighten 0%"), Some(0.0));
+        assert_eq!(parse_shade("darken 100%"), Some(-1.0));
+    }
+
+    #[test]
+    fn rejects_invalid_shades() {
+        for shade in [
+            "",
+            "lighten",
+            "lighten 10",
+            "lighten 101%",
+            "darken -5%",
+            "brighten 10%",
+            "Lighten 10%",
+            "lighten ten%",
+        ] {
+            assert_eq!(parse_shade(shade), None, "{:?}", shade);
+        }
+    }
+
+    fn table(toml: &str) -> Table {
+        toml.parse::<Table>().unwrap()
+    }
+
+    // One scope per manifest, each template being (name, fields, scope)
+    fn templates(entries: &[(&str, &str, usize)]) -> (Vec<Template>, Vec<HashMap<String, usize>>) {
+        let templates = entries
+            .iter()
+            .map(|(name, toml, scope)| Template {
+                name: name.to_string(),
+                table: table(toml),
+                scope: *scope,
+                manifest_path: PathBuf::from(format!("{}.toml", scope)),
+            })
+            .collect::<Vec<_>>();
+
+        let scope_count = entries
+            .iter()
+            .map(|(_, _, scope)| scope + 1)
+            .max()
+            .unwrap_or(1);
+        let mut scopes = vec![HashMap::new(); scope_count];
+        for (index, template) in templates.iter().enumerate() {
+            scopes[template.scope].insert(template.name.clone(), index);
+        }
+
+        (templates, scopes)
+    }
+
+    fn extend(toml: &str, templates: &(Vec<Template>, Vec<HashMap<String, usize>>)) -> Table {
+        extend_template(
+            &table(toml),
+            0,
+            &templates.0,
+            &templates.1,
+            &PathBuf::from("0.toml"),
+            &mut Vec::new(),
+        )
+    }
+
+    #[test]
+    fn extends_templates_recursively() {
+        let templates = templates(&[
+            (
+                "base",
+                "description = 'base'\ncategory = ['a']\ntags = ['x']",
+                0,
+            ),
+            ("smiley", "extends = 'base'\ndescription = 'smiley'", 0),
+        ]);
+
+        assert_eq!(
+            extend("extends = 'smiley'\ntags = ['y']", &templates),
+            table("description = 'smiley'\ncategory = ['a']\ntags = ['y']")
+        );
+        assert_eq!(extend("name = 'a'", &templates), table("name = 'a'"));
+    }
+
+    #[test]
+    fn appends_to_lists() {
+        let templates = templates(&[
+            ("base", "category = ['a']", 0),
+            ("more", "extends = 'base'\ncategory = { append = ['b'] }", 0),
+        ]);
+
+        assert_eq!(
+            extend(
+                "extends = 'more'\ncategory = { append = ['c'] }\ntags = { append = ['x'] }",
+                &templates
+            ),
+            table("category = ['a', 'b', 'c']\ntags = ['x']")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Field 'description' appends to a value that is not an array")]
+    fn rejects_appending_to_non_lists() {
+        let templates = templates(&[("base", "description = 'base'", 0)]);
+        extend(
+            "extends = 'base'\ndescription = { append = ['x'] }",
+            &templates,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Field 'category' must only contain an 'append' array")]
+    fn rejects_invalid_appends() {
+        extend("category = { append = 'x' }", &templates(&[]));
+    }
+
+    #[test]
+    fn resolves_templates_in_their_scope() {
+        // 'base' in scope 1 is only visible to templates defined in manifest 1
+        let templates = templates(&[
+            ("smiley", "extends = 'base'", 1),
+            ("base", "description = 'scope 1'", 1),
+            ("base", "description = 'scope 0'", 0),
+        ]);
+        let mut templates = templates;
+        templates.1[0].insert("smiley".to_string(), 0);
+
+        assert_eq!(
+            extend("extends = 'smiley'", &templates),
+            table("description = 'scope 1'")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Manifest extends template 'base', which is undefined in \"0.toml\"")]
+    fn rejects_undefined_templates() {
+        extend("extends = 'base'", &templates(&[]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Template 'a' extends itself through a -> b -> a in \"0.toml\"")]
+    fn reports_cycles() {
+        let templates = templates(&[("a", "extends = 'b'", 0), ("b", "extends = 'a'", 0)]);
+        extend("extends = 'a'", &templates);
+    }
+}
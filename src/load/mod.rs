@@ -1,7 +1,9 @@
 use std::path::PathBuf;
 
-mod colormap;
+mod animation;
+pub mod colormap;
 mod variable;
+mod xml;
 
 pub mod manifest;
 use manifest::Emoji;
@@ -13,7 +15,7 @@ pub mod svg;
 impl Pack {
     pub fn load_all(&mut self, index_path: &PathBuf) {
         self.logger.info("Loading build files");
-        let mut stage = self.logger.new_stage("Loading", 5);
+        let mut stage = self.logger.new_stage("Loading", 4);
 
         self.logger
             .load(&format!("Loading index manifest: {:?}", index_path));
@@ -24,10 +26,6 @@ impl Pack {
         stage.inc();
         self.load_svgs();
 
-        self.logger.load(&format!("Resolving variables"));
-        stage.inc();
-        self.resolve_variables();
-
         self.logger
             .load(&format!("Resolving colormaps and recoloring"));
         stage.inc();
@@ -36,6 +34,7 @@ impl Pack {
         // Clean up
         self.definitions.clear();
         self.colormaps.clear();
+        self.ramps.clear();
 
         self.logger
             .load(&format!("Successfully loaded {} emojis", self.emojis.len()));
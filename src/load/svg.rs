@@ -1,13 +1,24 @@
 use rayon::prelude::*;
-use regex::Regex;
-use resvg::usvg::Color;
-use std::{fmt, fs, path::PathBuf};
+use regex::{Captures, Regex};
+use roxmltree::{Document, NodeId};
+use std::{collections::HashMap, fmt, fs, path::PathBuf, sync::OnceLock};
 use svgcleaner::{
     cleaner::{clean_doc, parse_data},
     CleaningOptions, ParseOptions, WriteOptions,
 };
 
-use crate::load::Pack;
+use crate::load::{
+    animation::sample_smil,
+    manifest::{parse_color, Animation},
+    xml::{write_node, SVG_NS},
+    Pack,
+};
+
+// Declaration blocks in stylesheets, compiled once as every emoji is recolored many times
+fn css_block_re() -> &'static Regex {
+    static CSS_BLOCK_RE: OnceLock<Regex> = OnceLock::new();
+    CSS_BLOCK_RE.get_or_init(|| Regex::new(r"\{([^}]*)\}").unwrap())
+}
 
 #[derive(Clone)]
 pub struct Svg(pub String);
@@ -19,31 +30,33 @@ impl fmt::Debug for Svg {
 }
 
 impl Svg {
-    fn from_path(path: &PathBuf) -> Self {
-        match fs::read_to_string(path) {
-            Ok(svg) => {
-                let mut svgcleaner_doc = match parse_data(&svg, &ParseOptions::default()) {
-                    Ok(doc) => doc,
-                    Err(err) => panic!(
-                        "Error parsing (1 stage) SVG file at '{:?}' with error '{}'",
-                        path, err
-                    ),
-                };
+    fn from_str(svg: &str, path: &PathBuf) -> Self {
+        let mut svgcleaner_doc = match parse_data(svg, &ParseOptions::default()) {
+            Ok(doc) => doc,
+            Err(err) => panic!(
+                "Error parsing (1 stage) SVG file at '{:?}' with error '{}'",
+                path, err
+            ),
+        };
 
-                let cleaned_string = match clean_doc(
-                    &mut svgcleaner_doc,
-                    &CleaningOptions::default(),
-                    &WriteOptions::default(),
-                ) {
-                    Ok(_) => svgcleaner_doc.to_string(),
-                    Err(err) => panic!(
-                        "Error cleaning SVG file at '{:?}' with error '{}'",
-                        path, err
-                    ),
-                };
+        let cleaned_string = match clean_doc(
+            &mut svgcleaner_doc,
+            &CleaningOptions::default(),
+            &WriteOptions::default(),
+        ) {
+            Ok(_) => svgcleaner_doc.to_string(),
+            Err(err) => panic!(
+                "Error cleaning SVG file at '{:?}' with error '{}'",
+                path, err
+            ),
+        };
 
-                Self(cleaned_string)
-            }
+        Self(cleaned_string)
+    }
+
+    fn read(path: &PathBuf) -> String {
+        match fs::read_to_string(path) {
+            Ok(svg) => svg,
             Err(err) => panic!(
                 "Error reading SVG file at '{:?}' with error '{}'",
                 path, err
@@ -51,23 +64,340 @@ impl Svg {
         }
     }
 
-    pub fn replace_colors(&mut self, map: Vec<(Color, Color)>) {
-        for (from, to) in map {
-            let from = format!("#{:02X}{:02X}{:02X}", from.red, from.green, from.blue);
-            let to = format!("#{:02X}{:02X}{:02X}", to.red, to.green, to.blue);
+    fn from_path(path: &PathBuf) -> Self {
+        Self::from_str(&Self::read(path), path)
+    }
+
+    // Colors of every paint property, without alpha
+    pub fn colors(&self) -> Vec<[u8; 3]> {
+        let document = match Document::parse(&self.0) {
+            Ok(document) => document,
+            Err(err) => panic!("Error parsing cleaned SVG for recoloring: {}", err),
+        };
 
-            let regex_pattern = format!(r#"(?i){}"#, from);
-            let re = Regex::new(&regex_pattern).unwrap();
+        let css_block_re = css_block_re();
 
-            self.0 = re.replace_all(&self.0, &to).to_string();
+        let mut values: Vec<String> = Vec::new();
+        for node in document.descendants().filter(|node| node.is_element()) {
+            for (property, _) in PAINT_PROPERTIES {
+                if let Some(value) = node.attribute(property) {
+                    values.push(value.to_string());
+                }
+            }
+
+            let mut declarations = Vec::new();
+            if let Some(style) = node.attribute("style") {
+                declarations.extend(parse_declarations(style).unwrap_or_default());
+            }
+
+            if node.tag_name().namespace() == Some(SVG_NS) && node.tag_name().name() == "style" {
+                for text in node.children().filter(|child| child.is_text()) {
+                    for block in css_block_re.captures_iter(text.text().unwrap_or("")) {
+                        declarations.extend(parse_declarations(&block[1]).unwrap_or_default());
+                    }
+                }
+            }
+
+            for (property, value) in declarations {
+                if PAINT_PROPERTIES.iter().any(|(paint, _)| *paint == property) {
+                    values.push(value);
+                }
+            }
         }
+
+        let mut colors = Vec::new();
+        for color in values.iter().filter_map(|value| parse_color(value)) {
+            let color = [color[0], color[1], color[2]];
+            if !colors.contains(&color) {
+                colors.push(color);
+            }
+        }
+
+        colors
     }
+
+    // Every color is replaced at once, so a target color is never mapped again.
+    // Returns whether each source color was found, and the properties that lost an alpha change
+    pub fn replace_colors(&mut self, map: &[Recolor]) -> (Vec<bool>, Vec<String>) {
+        let mut found = vec![false; map.len()];
+        let mut dropped: Vec<String> = Vec::new();
+
+        let document = match Document::parse(&self.0) {
+            Ok(document) => document,
+            Err(err) => panic!("Error parsing cleaned SVG for recoloring: {}", err),
+        };
+
+        let css_block_re = css_block_re();
+
+        let mut overrides: HashMap<(NodeId, String), String> = HashMap::new();
+        for node in document.descendants().filter(|node| node.is_element()) {
+            for (property, opacity_property) in PAINT_PROPERTIES {
+                let value = match node.attribute(property) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let opacity =
+                    opacity_property.and_then(|opacity_property| node.attribute(opacity_property));
+
+                let (color, opacity) =
+                    match recolor_value(value, property, opacity, map, &mut found) {
+                        Some(recolored) => recolored,
+                        None => continue,
+                    };
+
+                overrides.insert((node.id(), property.to_string()), color);
+
+                match (opacity_property, opacity) {
+                    (Some(opacity_property), Some(opacity)) => {
+                        overrides.insert((node.id(), opacity_property.to_string()), opacity);
+                    }
+                    (None, Some(_)) => drop_alpha(property, &mut dropped),
+                    _ => (),
+                }
+            }
+
+            if let Some(style) = node.attribute("style") {
+                if let Some(style) = recolor_declarations(style, map, &mut found, &mut dropped) {
+                    overrides.insert((node.id(), "style".to_string()), style);
+                }
+            }
+
+            // Declarations in stylesheets
+            if node.tag_name().namespace() == Some(SVG_NS) && node.tag_name().name() == "style" {
+                for text in node.children().filter(|child| child.is_text()) {
+                    let css = text.text().unwrap_or("");
+                    let recolored =
+                        css_block_re.replace_all(
+                            css,
+                            |block: &Captures| match recolor_declarations(
+                                &block[1],
+                                map,
+                                &mut found,
+                                &mut dropped,
+                            ) {
+                                Some(declarations) => format!("{{{}}}", declarations),
+                                None => block[0].to_string(),
+                            },
+                        );
+
+                    if recolored != css {
+                        overrides.insert((text.id(), "#text".to_string()), recolored.to_string());
+                    }
+                }
+            }
+        }
+
+        if !overrides.is_empty() {
+            // Keep the XML declaration
+            let root = document.root_element();
+            let mut output = self.0[..root.range().start].to_string();
+            write_node(&root, &overrides, &|_| false, &mut output);
+
+            self.0 = output;
+        }
+
+        (found, dropped)
+    }
+}
+
+// Paint properties, with the opacity property that alpha changes are applied to
+pub const PAINT_PROPERTIES: [(&str, Option<&str>); 6] = [
+    ("fill", Some("fill-opacity")),
+    ("stroke", Some("stroke-opacity")),
+    ("stop-color", Some("stop-opacity")),
+    ("flood-color", Some("flood-opacity")),
+    ("lighting-color", None),
+    ("color", None),
+];
+
+// A colormap entry. Sources with alpha only match paints with that opacity,
+// the opacity of the target replaces it, or is scaled by the alpha change
+#[derive(Clone, Debug)]
+pub struct Recolor {
+    pub from: [u8; 4],
+    pub to: [u8; 4],
+    pub opacity: Option<f64>,
+    pub properties: Option<Vec<String>>,
+}
+
+impl Recolor {
+    fn applies_to(&self, property: &str) -> bool {
+        match &self.properties {
+            Some(properties) => properties.iter().any(|other| other == property),
+            None => true,
+        }
+    }
+
+    // Whether both could match the same paint
+    pub fn overlaps(&self, other: &Recolor) -> bool {
+        self.from == other.from
+            && match (&self.properties, &other.properties) {
+                (Some(properties), Some(other)) => {
+                    properties.iter().any(|property| other.contains(property))
+                }
+                _ => true,
+            }
+    }
+}
+
+fn parse_opacity(opacity: Option<&str>) -> f64 {
+    match opacity.map(|opacity| opacity.trim()) {
+        Some(opacity) => match opacity.strip_suffix('%') {
+            Some(percent) => percent.parse::<f64>().map(|percent| percent / 100.0),
+            None => opacity.parse::<f64>(),
+        }
+        .unwrap_or(1.0),
+        None => 1.0,
+    }
+}
+
+// The new color, and the new opacity if it changes
+fn recolor_value(
+    value: &str,
+    property: &str,
+    opacity: Option<&str>,
+    map: &[Recolor],
+    found: &mut [bool],
+) -> Option<(String, Option<String>)> {
+    let color = parse_color(value)?;
+    let opacity = parse_opacity(opacity);
+
+    let matches =
+        |recolor: &Recolor| recolor.from[..3] == color[..3] && recolor.applies_to(property);
+    let index = map
+        .iter()
+        .position(|recolor| {
+            matches(recolor)
+                && recolor.from[3] != 255
+                && (opacity * 255.0).round() as u8 == recolor.from[3]
+        })
+        .or_else(|| {
+            map.iter()
+                .position(|recolor| matches(recolor) && recolor.from[3] == 255)
+        })?;
+    found[index] = true;
+
+    let recolor = &map[index];
+    let to = recolor.to;
+    let hex = if color[3] == 255 {
+        format!("#{:02X}{:02X}{:02X}", to[0], to[1], to[2])
+    } else {
+        format!("#{:02X}{:02X}{:02X}{:02X}", to[0], to[1], to[2], color[3])
+    };
+
+    let opacity = match recolor.opacity {
+        Some(opacity) => Some(opacity),
+        None if recolor.from[3] != to[3] => {
+            Some(opacity * to[3] as f64 / recolor.from[3].max(1) as f64)
+        }
+        None => None,
+    };
+
+    Some((
+        hex,
+        opacity.map(|opacity| format!("{}", (opacity.clamp(0.0, 1.0) * 1000.0).round() / 1000.0)),
+    ))
+}
+
+// Properties without an opacity property can't take an alpha change, only the color is replaced
+fn drop_alpha(property: &str, dropped: &mut Vec<String>) {
+    if !dropped.iter().any(|other| other == property) {
+        dropped.push(property.to_string());
+    }
+}
+
+// CSS declarations, as in a style attribute
+fn parse_declarations(declarations: &str) -> Option<Vec<(String, String)>> {
+    declarations
+        .split(';')
+        .filter(|declaration| !declaration.trim().is_empty())
+        .map(|declaration| {
+            declaration
+                .split_once(':')
+                .map(|(property, value)| (property.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+// Returns None if nothing was recolored
+fn recolor_declarations(
+    declarations: &str,
+    map: &[Recolor],
+    found: &mut [bool],
+    dropped: &mut Vec<String>,
+) -> Option<String> {
+    let mut declarations = parse_declarations(declarations)?;
+
+    let mut recolored = false;
+    for (property, opacity_property) in PAINT_PROPERTIES {
+        let index = match declarations.iter().position(|(name, _)| name == property) {
+            Some(index) => index,
+            None => continue,
+        };
+
+        let opacity_index = opacity_property.and_then(|opacity_property| {
+            declarations
+                .iter()
+                .position(|(name, _)| name == opacity_property)
+        });
+        let opacity = opacity_index.map(|opacity_index| declarations[opacity_index].1.as_str());
+
+        let (color, opacity) =
+            match recolor_value(&declarations[index].1, property, opacity, map, found) {
+                Some(recolored) => recolored,
+                None => continue,
+            };
+
+        declarations[index].1 = color;
+        recolored = true;
+
+        match (opacity_property, opacity) {
+            (Some(opacity_property), Some(opacity)) => match opacity_index {
+                Some(opacity_index) => declarations[opacity_index].1 = opacity,
+                None => declarations.push((opacity_property.to_string(), opacity)),
+            },
+            (None, Some(_)) => drop_alpha(property, dropped),
+            _ => (),
+        }
+    }
+
+    recolored.then(|| {
+        declarations
+            .iter()
+            .map(|(property, value)| format!("{}:{}", property, value))
+            .collect::<Vec<_>>()
+            .join(";")
+    })
 }
 
 impl Pack {
     pub fn load_svgs(&mut self) {
         self.emojis.par_iter_mut().for_each(|emoji| {
-            let svg_tree = Svg::from_path(&emoji.src);
+            emoji.frames = match &emoji.animation {
+                Some(Animation::Frames(frames)) => frames
+                    .iter()
+                    .map(|(path, duration)| (Svg::from_path(path), *duration))
+                    .collect(),
+                // Frames are sampled from the original file, before cleaning
+                Some(Animation::Smil {
+                    frame_rate,
+                    duration,
+                }) => sample_smil(
+                    &Svg::read(&emoji.src),
+                    *frame_rate,
+                    *duration,
+                    &format!("{:?}", emoji.src),
+                )
+                .iter()
+                .map(|(frame, duration)| (Svg::from_str(frame, &emoji.src), *duration))
+                .collect(),
+                None => Vec::new(),
+            };
+
+            let svg_tree = match emoji.frames.first() {
+                Some((svg, _)) => svg.clone(),
+                None => Svg::from_path(&emoji.src),
+            };
             emoji.svg = Some(svg_tree);
         });
     }
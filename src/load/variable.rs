@@ -1,113 +1,331 @@
-use crate::load::Pack;
-
-impl Pack {
-    pub fn resolve_variables(&mut self) {
-        // Colormaps
-        for (_, colormap) in &mut self.colormaps {
-            for (key, value) in &colormap.entries.clone() {
-                if key.starts_with("$") {
-                    match self.definitions.get(key) {
-                        Some(variable) => {
-                            colormap.entries.remove(key);
-                            colormap.entries.insert(variable.clone(), value.clone());
-                        }
-                        None => panic!(
-                            "Colormap uses variable '{}' in key, which is undefined",
-                            key
-                        ),
-                    }
-                }
-            }
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-            for (key, value) in &colormap.entries.clone() {
-                if value.starts_with("$") {
-                    match self.definitions.get(value) {
-                        Some(variable) => {
-                            colormap.entries.remove(key);
-                            colormap.entries.insert(key.clone(), variable.clone());
-                        }
-                        None => panic!(
-                            "Colormap uses variable '{}' in value, which is undefined",
-                            key
-                        ),
-                    }
-                }
-            }
+use toml::Value;
+
+use crate::load::manifest::Definition;
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+// Resolves a variable, along with the variables in its value, keeping track of the chain
+// of variables being resolved to catch cycles
+fn lookup(
+    definitions: &[HashMap<String, Definition>],
+    scope: usize,
+    name: &str,
+    manifest_path: &PathBuf,
+    chain: &mut Vec<(String, usize)>,
+) -> String {
+    let name = format!("${}", name);
+
+    let definition = match definitions[scope].get(&name) {
+        Some(definition) => definition,
+        None if chain.is_empty() => panic!(
+            "Manifest uses variable '{}', which is undefined in {:?}",
+            name, manifest_path
+        ),
+        None => panic!(
+            "Variable '{}' uses variable '{}', which is undefined in {:?}",
+            chain.last().unwrap().0,
+            name,
+            manifest_path
+        ),
+    };
+
+    // Variables with the same name in different scopes are different variables
+    let variable = (name, definition.scope);
+
+    if chain.contains(&variable) {
+        let cycle = chain
+            .iter()
+            .skip_while(|other| **other != variable)
+            .collect::<Vec<_>>();
+
+        panic!(
+            "Variable '{}' refers to itself through {} -> {} ({})",
+            variable.0,
+            cycle
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" -> "),
+            variable.0,
+            cycle
+                .iter()
+                .map(|(name, scope)| format!(
+                    "{} defined in {:?}",
+                    name, definitions[*scope][name].manifest_path
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    chain.push(variable);
+    let value = interpolate_chain(
+        &definition.value,
+        definitions,
+        definition.scope,
+        &definition.manifest_path,
+        chain,
+    );
+    chain.pop();
+
+    value
+}
+
+fn interpolate_chain(
+    text: &str,
+    definitions: &[HashMap<String, Definition>],
+    scope: usize,
+    manifest_path: &PathBuf,
+    chain: &mut Vec<(String, usize)>,
+) -> String {
+    if !text.contains('$') {
+        return text.to_string();
+    }
+
+    let mut result = String::new();
+    let mut rest = text;
 
-            if let Some(codepoint) = &mut colormap.codepoint {
-                let mut new_codepoint: Vec<String> = Vec::new();
-
-                for component in codepoint.iter() {
-                    if component.starts_with("$") {
-                        match self.definitions.get(component) {
-                            Some(variable) => {
-                                for variable_component in variable.split(" ") {
-                                    new_codepoint.push(variable_component.to_string());
-                                }
-                            }
-                            None => panic!(
-                                "Colormap uses variable '{}' in codepoint, which is undefined",
-                                component
-                            ),
-                        }
-
-                        continue;
-                    }
-
-                    new_codepoint.push(component.clone());
-                }
-
-                *codepoint = new_codepoint;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = match after.find('}') {
+                Some(end) => end,
+                None => panic!(
+                    "Manifest contains unterminated '${{' in '{}' in {:?}",
+                    text, manifest_path
+                ),
             };
+
+            result.push_str(&lookup(
+                definitions,
+                scope,
+                &after[..end],
+                manifest_path,
+                chain,
+            ));
+            rest = &after[end + 1..];
+        } else {
+            // Names end before any trailing punctuation, such as a full stop
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let end = rest[..end].trim_end_matches(['.', '-']).len();
+
+            if end == 0 {
+                panic!(
+                    "Manifest contains '$' without a variable name in '{}' (use '$$' for a literal '$') in {:?}",
+                    text, manifest_path
+                );
+            }
+
+            result.push_str(&lookup(
+                definitions,
+                scope,
+                &rest[..end],
+                manifest_path,
+                chain,
+            ));
+            rest = &rest[end..];
         }
+    }
 
-        // Emojis
-        for emoji in &mut self.emojis {
-            if let Some(codepoint) = &mut emoji.codepoint {
-                let mut new_codepoint: Vec<String> = Vec::new();
-
-                for codepoint_component in &codepoint.clone() {
-                    if codepoint_component.starts_with("$") {
-                        match self.definitions.get(codepoint_component) {
-                            Some(variable) => {
-                                for variable_component in variable.split(" ") {
-                                    new_codepoint.push(variable_component.to_string());
-                                }
-                            }
-                            None => panic!(
-                                "Emoji uses variable '{}' in codepoint, which is undefined",
-                                codepoint_component
-                            ),
-                        }
-                    } else {
-                        new_codepoint.push(codepoint_component.clone());
-                    }
-                }
-
-                *codepoint = new_codepoint;
-            };
+    result.push_str(rest);
+    result
+}
 
-            let mut new_colormaps: Vec<String> = Vec::new();
-
-            for colormap in &mut emoji.colormaps {
-                if colormap.starts_with("$") {
-                    match self.definitions.get(colormap) {
-                        Some(variable) => {
-                            for variable_component in variable.split(" ") {
-                                new_colormaps.push(variable_component.to_string());
-                            }
-                        }
-                        None => panic!(
-                            "Emoji uses variable '{}' in colormap, which is undefined",
-                            colormap
-                        ),
-                    }
-                } else {
-                    new_colormaps.push(colormap.clone());
-                }
+// Replaces `$name` and `${name}` with their definitions, and `$$` with a literal `$`
+pub fn interpolate(
+    text: &str,
+    definitions: &[HashMap<String, Definition>],
+    scope: usize,
+    manifest_path: &PathBuf,
+) -> String {
+    interpolate_chain(text, definitions, scope, manifest_path, &mut Vec::new())
+}
+
+// Interpolates every string and table key in a manifest value
+pub fn interpolate_value(
+    value: &mut Value,
+    definitions: &[HashMap<String, Definition>],
+    scope: usize,
+    manifest_path: &PathBuf,
+) {
+    match value {
+        Value::String(text) => *text = interpolate(text, definitions, scope, manifest_path),
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate_value(value, definitions, scope, manifest_path);
             }
+        }
+        Value::Table(table) => {
+            *table = std::mem::take(table)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    interpolate_value(&mut value, definitions, scope, manifest_path);
+                    (interpolate(&key, definitions, scope, manifest_path), value)
+                })
+                .collect();
+        }
+        _ => (),
+    }
+}
 
-            emoji.colormaps = new_colormaps;
+// Catches undefined variables and cycles, even in unused definitions
+pub fn check_definitions(definitions: &[HashMap<String, Definition>]) {
+    for (scope, scope_definitions) in definitions.iter().enumerate() {
+        let mut names = scope_definitions
+            .iter()
+            .filter(|(_, definition)| definition.scope == scope)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+
+        for name in names {
+            let definition = &scope_definitions[name];
+            interpolate_chain(
+                &definition.value,
+                definitions,
+                scope,
+                &definition.manifest_path,
+                &mut vec![(name.clone(), scope)],
+            );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One scope per manifest, each entry being (name, value, defining scope)
+    fn definitions(scopes: &[&[(&str, &str, usize)]]) -> Vec<HashMap<String, Definition>> {
+        scopes
+            .iter()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|(name, value, scope)| {
+                        (
+                            name.to_string(),
+                            Definition {
+                                value: value.to_string(),
+                                manifest_path: PathBuf::from(format!("{}.toml", scope)),
+                                scope: *scope,
+                            },
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn run(text: &str, definitions: &[HashMap<String, Definition>]) -> String {
+        interpolate(text, definitions, 0, &PathBuf::from("0.toml"))
+    }
+
+    #[test]
+    fn interpolates_names() {
+        let definitions = definitions(&[&[("$skin", "light", 0), ("$zwj", "U+200D", 0)]]);
+
+        assert_eq!(run("$skin", &definitions), "light");
+        assert_eq!(run("Hand ($skin)", &definitions), "Hand (light)");
+        assert_eq!(run("${skin}er", &definitions), "lighter");
+        assert_eq!(run("$skin $zwj", &definitions), "light U+200D");
+        assert_eq!(run("no variables", &definitions), "no variables");
+    }
+
+    #[test]
+    fn trims_trailing_punctuation() {
+        let definitions = definitions(&[&[("$skin", "light", 0), ("$skin.1", "pale", 0)]]);
+
+        assert_eq!(run("Skin is $skin.", &definitions), "Skin is light.");
+        assert_eq!(run("$skin-", &definitions), "light-");
+        assert_eq!(run("$skin.1", &definitions), "pale");
+        assert_eq!(run("$skin.1.", &definitions), "pale.");
+    }
+
+    #[test]
+    fn escapes_dollars() {
+        let definitions = definitions(&[&[("$skin", "light", 0)]]);
+
+        assert_eq!(run("$$skin", &definitions), "$skin");
+        assert_eq!(run("$$$skin", &definitions), "$light");
+        assert_eq!(run("5$$", &definitions), "5$");
+    }
+
+    #[test]
+    #[should_panic(expected = "Manifest uses variable '$tone', which is undefined")]
+    fn rejects_undefined() {
+        run("$tone", &definitions(&[&[("$skin", "light", 0)]]));
+    }
+
+    #[test]
+    #[should_panic(expected = "unterminated '${'")]
+    fn rejects_unterminated_braces() {
+        run("${skin", &definitions(&[&[("$skin", "light", 0)]]));
+    }
+
+    #[test]
+    #[should_panic(expected = "'$' without a variable name")]
+    fn rejects_lone_dollar() {
+        run("5$", &definitions(&[&[]]));
+    }
+
+    #[test]
+    fn resolves_recursively() {
+        let definitions = definitions(&[&[
+            ("$skin", "$tone.1", 0),
+            ("$tone.1", "${shade}er", 0),
+            ("$shade", "light", 0),
+        ]]);
+
+        assert_eq!(run("Hand ($skin)", &definitions), "Hand (lighter)");
+    }
+
+    #[test]
+    fn resolves_in_the_defining_scope() {
+        // Manifest 1 sees the exported $skin of manifest 0, which uses manifest 0's $tone
+        let definitions = definitions(&[
+            &[("$skin", "$tone", 0), ("$tone", "light", 0)],
+            &[("$skin", "$tone", 0), ("$tone", "dark", 1)],
+        ]);
+
+        assert_eq!(
+            interpolate("$skin $tone", &definitions, 1, &PathBuf::from("1.toml")),
+            "light dark"
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Variable '$skin' uses variable '$tone', which is undefined in \"0.toml\""
+    )]
+    fn rejects_undefined_in_definitions() {
+        run("$skin", &definitions(&[&[("$skin", "$tone", 0)]]));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Variable '$a' refers to itself through $a -> $b -> $a ($a defined in \"0.toml\", $b defined in \"0.toml\")"
+    )]
+    fn reports_cycles() {
+        run(
+            "$c",
+            &definitions(&[&[("$a", "$b", 0), ("$b", "$a", 0), ("$c", "$a", 0)]]),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Variable '$a' refers to itself through $a -> $a")]
+    fn checks_unused_definitions() {
+        check_definitions(&definitions(&[&[("$a", "$a", 0)]]));
+    }
+}
@@ -0,0 +1,110 @@
+use roxmltree::{Node, NodeId, NodeType};
+use std::collections::HashMap;
+
+pub const SVG_NS: &str = "http://www.w3.org/2000/svg";
+pub const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+fn qualified_name(node: &Node, namespace: Option<&str>, name: &str) -> String {
+    match namespace {
+        None | Some(SVG_NS) => name.to_string(),
+        Some(namespace) => match node.lookup_prefix(namespace) {
+            Some(prefix) => format!("{}:{}", prefix, name),
+            None if namespace == XLINK_NS => format!("xlink:{}", name),
+            None => name.to_string(),
+        },
+    }
+}
+
+pub fn attribute_value(node: &Node, name: &str) -> Option<String> {
+    node.attributes()
+        .find(|attribute| qualified_name(node, attribute.namespace(), attribute.name()) == name)
+        .map(|attribute| attribute.value().to_string())
+}
+
+fn escape(value: &str, attribute: bool) -> String {
+    let value = value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+
+    if attribute {
+        value.replace('"', "&quot;")
+    } else {
+        value
+    }
+}
+
+// Elements matching skip are left out, overrides replace or add attributes,
+// or replace the content of text nodes with the name "#text"
+pub fn write_node(
+    node: &Node,
+    overrides: &HashMap<(NodeId, String), String>,
+    skip: &dyn Fn(&Node) -> bool,
+    output: &mut String,
+) {
+    match node.node_type() {
+        NodeType::Element => {
+            if skip(node) {
+                return;
+            }
+
+            let tag = node.tag_name();
+            let name = qualified_name(node, tag.namespace(), tag.name());
+            output.push('<');
+            output.push_str(&name);
+
+            // Declare every namespace on the root element
+            if node.parent_element().is_none() {
+                for namespace in node.namespaces() {
+                    match namespace.name() {
+                        Some(prefix) => output.push_str(&format!(" xmlns:{}", prefix)),
+                        None => output.push_str(" xmlns"),
+                    }
+                    output.push_str(&format!("=\"{}\"", escape(namespace.uri(), true)));
+                }
+            }
+
+            let mut written = Vec::new();
+            for attribute in node.attributes() {
+                let attribute_name = qualified_name(node, attribute.namespace(), attribute.name());
+                let value = match overrides.get(&(node.id(), attribute_name.clone())) {
+                    Some(value) => value.as_str(),
+                    None => attribute.value(),
+                };
+
+                output.push_str(&format!(" {}=\"{}\"", attribute_name, escape(value, true)));
+                written.push(attribute_name);
+            }
+
+            let mut added = overrides
+                .iter()
+                .filter(|((id, attribute), _)| *id == node.id() && !written.contains(attribute))
+                .collect::<Vec<_>>();
+            added.sort_by_key(|((_, attribute), _)| attribute.clone());
+
+            for ((_, attribute), value) in added {
+                output.push_str(&format!(" {}=\"{}\"", attribute, escape(value, true)));
+            }
+
+            if !node.has_children() {
+                output.push_str("/>");
+                return;
+            }
+
+            output.push('>');
+            for child in node.children() {
+                write_node(&child, overrides, skip, output);
+            }
+            output.push_str(&format!("</{}>", name));
+        }
+        NodeType::Text => {
+            let text = match overrides.get(&(node.id(), "#text".to_string())) {
+                Some(text) => text.as_str(),
+                None => node.text().unwrap_or(""),
+            };
+
+            output.push_str(&escape(text, false));
+        }
+        _ => {}
+    }
+}
@@ -189,6 +189,18 @@ impl Logger {
         }
     }
 
+    pub fn warn(&mut self, message: &str) {
+        match &mut self.total_bar {
+            Bar::Tty(_) => {
+                self.total_bar
+                    .write(&format!("{} {}", "WARN ".colorize("bold yellow"), message));
+            }
+            Bar::Notty(_) => {
+                println!("WARN  {}", message);
+            }
+        }
+    }
+
     pub fn build(&mut self, message: &str) {
         match &mut self.total_bar {
             Bar::Tty(_) => {
@@ -61,6 +61,9 @@ fn main() {
     let mut pack = Pack::new(logger, output_path);
 
     pack.load_all(&manifest_path);
+    if !dry {
+        pack.save_colormap_report();
+    }
     pack.build_tags(tags, dry);
 
     if let Some(save_thread) = pack.save_thread.take() {
@@ -1,4 +1,5 @@
-use crate::load::manifest::{Colormap, Emoji, Target};
+use crate::load::colormap::ColormapReport;
+use crate::load::manifest::{Colormap, Definition, Emoji, Target};
 use crate::process::cache::Cache;
 use crate::Logger;
 use std::collections::HashMap;
@@ -8,10 +9,12 @@ use std::thread::JoinHandle;
 #[derive(Debug)]
 pub struct Pack {
     pub cache: Cache,
-    pub colormaps: HashMap<String, Colormap>,
+    pub colormaps: Vec<Colormap>,
+    pub colormap_report: Vec<ColormapReport>,
+    pub ramps: Vec<HashMap<String, HashMap<String, f64>>>,
     pub emojis: Vec<Emoji>,
     pub targets: Vec<Target>,
-    pub definitions: HashMap<String, String>,
+    pub definitions: Vec<HashMap<String, Definition>>,
     pub output_path: PathBuf,
     pub logger: Logger,
     pub save_thread: Option<JoinHandle<()>>,
@@ -21,10 +24,12 @@ impl Pack {
     pub fn new(logger: Logger, output_path: PathBuf) -> Self {
         Self {
             cache: Cache::new(&output_path),
-            colormaps: HashMap::new(),
+            colormaps: Vec::new(),
+            colormap_report: Vec::new(),
+            ramps: Vec::new(),
             emojis: Vec::new(),
             targets: Vec::new(),
-            definitions: HashMap::new(),
+            definitions: Vec::new(),
             output_path,
             logger,
             save_thread: None,
@@ -0,0 +1,167 @@
+use image::RgbaImage;
+
+use crate::process::encode::{decode_raster, EncodeTarget};
+
+// Peak signal-to-noise ratio over every channel, infinite for identical images
+fn psnr(reference: &RgbaImage, image: &RgbaImage) -> f64 {
+    if reference.dimensions() != image.dimensions() {
+        return 0.0;
+    }
+
+    // Color is weighted by alpha, lossy encoders may change the color of transparent pixels
+    let weighted = |pixel: &image::Rgba<u8>| {
+        let alpha = pixel[3] as f64 / 255.0;
+        [
+            pixel[0] as f64 * alpha,
+            pixel[1] as f64 * alpha,
+            pixel[2] as f64 * alpha,
+            pixel[3] as f64,
+        ]
+    };
+
+    let squared_error: f64 = reference
+        .pixels()
+        .zip(image.pixels())
+        .map(|(a, b)| {
+            let (a, b) = (weighted(a), weighted(b));
+            (0..4)
+                .map(|channel| (a[channel] - b[channel]).powi(2))
+                .sum::<f64>()
+        })
+        .sum();
+
+    if squared_error == 0.0 {
+        return f64::INFINITY;
+    }
+
+    let mean_squared_error = squared_error / reference.as_raw().len() as f64;
+    10.0 * (255.0 * 255.0 / mean_squared_error).log10()
+}
+
+// Index of the smallest candidate meeting the quality threshold,
+// or of the best looking one if none of them do
+pub fn pick_smallest(
+    candidates: &[(String, EncodeTarget)],
+    encoded: &[Vec<u8>],
+    reference: Option<&RgbaImage>,
+    min_psnr: Option<f64>,
+) -> usize {
+    let quality = candidates
+        .iter()
+        .zip(encoded.iter())
+        .map(|((_, format), data)| match reference {
+            Some(reference) if !format.is_lossless() => match decode_raster(data, format) {
+                Some(decoded) => psnr(reference, &decoded),
+                None => 0.0,
+            },
+            _ => f64::INFINITY,
+        })
+        .collect::<Vec<_>>();
+
+    let passing = (0..candidates.len())
+        .filter(|index| match min_psnr {
+            Some(min_psnr) => quality[*index] >= min_psnr,
+            None => true,
+        })
+        .min_by_key(|index| encoded[*index].len());
+
+    match passing {
+        Some(index) => index,
+        None => (0..candidates.len())
+            .max_by(|a, b| quality[*a].total_cmp(&quality[*b]))
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::encode::encode_raster;
+    use image::Rgba;
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(32, 32, |x, y| {
+            Rgba([(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8, 255])
+        })
+    }
+
+    fn webp(quality: f32) -> (String, EncodeTarget) {
+        let format = EncodeTarget::Webp {
+            lossless: false,
+            quality,
+            method: 4,
+        };
+        (format!("webp-{}", quality), format)
+    }
+
+    fn png() -> (String, EncodeTarget) {
+        ("png".to_string(), EncodeTarget::PngImage)
+    }
+
+    #[test]
+    fn computes_psnr() {
+        let reference = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        assert_eq!(psnr(&reference, &reference), f64::INFINITY);
+        assert_eq!(psnr(&reference, &RgbaImage::new(2, 1)), 0.0);
+
+        // One channel off by 255 over 16 values
+        let mut image = reference.clone();
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        assert!((psnr(&reference, &image) - 10.0 * 16f64.log10()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ignores_color_of_transparent_pixels() {
+        let reference = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0]));
+        let image = RgbaImage::from_pixel(2, 2, Rgba([255, 128, 0, 0]));
+        assert_eq!(psnr(&reference, &image), f64::INFINITY);
+    }
+
+    #[test]
+    fn picks_smallest_without_threshold() {
+        let candidates = [png(), png(), png()];
+        let encoded = [vec![0; 30], vec![0; 10], vec![0; 20]];
+        assert_eq!(pick_smallest(&candidates, &encoded, None, None), 1);
+    }
+
+    #[test]
+    fn picks_smallest_passing_candidate() {
+        let reference = gradient();
+        let candidates = [png(), webp(0.0), webp(90.0)];
+        let encoded = candidates
+            .iter()
+            .map(|(_, format)| encode_raster(&reference, format))
+            .collect::<Vec<_>>();
+        assert!(encoded[1].len() < encoded[2].len() && encoded[2].len() < encoded[0].len());
+
+        let quality = |index: usize| {
+            psnr(
+                &reference,
+                &decode_raster(&encoded[index], &candidates[index].1).unwrap(),
+            )
+        };
+        let (low, high) = (quality(1), quality(2));
+        assert!(low < high);
+
+        // Lossless candidates always pass
+        let pick = |min_psnr| pick_smallest(&candidates, &encoded, Some(&reference), min_psnr);
+        assert_eq!(pick(None), 1);
+        assert_eq!(pick(Some(low)), 1);
+        assert_eq!(pick(Some((low + high) / 2.0)), 2);
+        assert_eq!(pick(Some(high + 1.0)), 0);
+    }
+
+    #[test]
+    fn picks_best_quality_when_none_pass() {
+        let reference = gradient();
+        let candidates = [webp(90.0), webp(0.0)];
+        let encoded = candidates
+            .iter()
+            .map(|(_, format)| encode_raster(&reference, format))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pick_smallest(&candidates, &encoded, Some(&reference), Some(f64::MAX)),
+            0
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::process::encode::EncodeTarget;
+use crate::process::{effects::Effect, encode::EncodeTarget, rasterize::RenderOptions};
 use std::{fmt, fs, path::PathBuf};
 
 pub struct Cache {
@@ -11,8 +11,18 @@ impl Cache {
         Self { path }
     }
 
-    pub fn try_get(&self, svg: &str, format: &EncodeTarget, size: u32) -> Option<Vec<u8>> {
-        let hash = md5::compute(format!("{}-{:?}-{}", svg, format, size));
+    pub fn try_get(
+        &self,
+        svg: &str,
+        format: &EncodeTarget,
+        size: u32,
+        render: &RenderOptions,
+        effects: &[Effect],
+    ) -> Option<Vec<u8>> {
+        let hash = md5::compute(format!(
+            "{}-{:?}-{}-{:?}-{:?}",
+            svg, format, size, render, effects
+        ));
         let hash = format!("{:x}", hash);
 
         let mut path = self.path.join(hash);
@@ -24,13 +34,24 @@ impl Cache {
         }
     }
 
-    pub fn save(&self, svg: &str, format: &EncodeTarget, size: u32, raster: &Vec<u8>) {
+    pub fn save(
+        &self,
+        svg: &str,
+        format: &EncodeTarget,
+        size: u32,
+        render: &RenderOptions,
+        effects: &[Effect],
+        raster: &Vec<u8>,
+    ) {
         match fs::create_dir_all(&self.path) {
             Ok(_) => {}
             Err(err) => panic!("Failed to create cache directory: {}", err),
         }
 
-        let hash = md5::compute(format!("{}-{:?}-{}", svg, format, size));
+        let hash = md5::compute(format!(
+            "{}-{:?}-{}-{:?}-{:?}",
+            svg, format, size, render, effects
+        ));
         let hash = format!("{:x}", hash);
 
         let mut path = self.path.join(hash);
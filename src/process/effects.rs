@@ -0,0 +1,215 @@
+use image::RgbaImage;
+use rayon::prelude::*;
+
+use crate::process::resample::{downscale, DownscaleFilter};
+
+// Lengths are fractions of the image width, so effects look the same at every size
+#[derive(Clone, Debug)]
+pub enum Effect {
+    Padding(f64),
+    Outline {
+        width: f64,
+        color: [u8; 4],
+    },
+    Shadow {
+        offset: (f64, f64),
+        blur: f64,
+        color: [u8; 4],
+    },
+}
+
+// Pixels are premultiplied, as rendered by resvg
+fn premultiply([r, g, b, a]: [u8; 4]) -> [f32; 4] {
+    let alpha = a as f32 / 255.0;
+    [
+        r as f32 * alpha,
+        g as f32 * alpha,
+        b as f32 * alpha,
+        a as f32,
+    ]
+}
+
+// Fills a coverage mask with a color and draws the image over it
+fn composite_under(image: &mut RgbaImage, mask: &[f32], color: [u8; 4]) {
+    let color = premultiply(color);
+
+    image
+        .pixels_mut()
+        .zip(mask.iter())
+        .for_each(|(pixel, coverage)| {
+            let remaining = 1.0 - pixel[3] as f32 / 255.0;
+
+            for channel in 0..4 {
+                let value = pixel[channel] as f32 + color[channel] * coverage * remaining;
+                pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+}
+
+fn alpha_mask(image: &RgbaImage) -> Vec<f32> {
+    image
+        .pixels()
+        .map(|pixel| pixel[3] as f32 / 255.0)
+        .collect()
+}
+
+fn padding(image: &RgbaImage, amount: f64) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let inner_width = ((width as f64 * (1.0 - amount * 2.0)).round() as u32).max(1);
+    let inner_height = ((height as f64 * (1.0 - amount * 2.0)).round() as u32).max(1);
+
+    let inner = downscale(
+        image.as_raw(),
+        width,
+        height,
+        inner_width,
+        inner_height,
+        &DownscaleFilter::Lanczos,
+    );
+    let inner = RgbaImage::from_raw(inner_width, inner_height, inner).unwrap();
+
+    let mut padded = RgbaImage::new(width, height);
+    image::imageops::replace(
+        &mut padded,
+        &inner,
+        ((width - inner_width) / 2) as i64,
+        ((height - inner_height) / 2) as i64,
+    );
+
+    padded
+}
+
+// Grows the alpha mask by a radius, with antialiased edges
+fn dilate(mask: &[f32], width: u32, height: u32, radius: f32) -> Vec<f32> {
+    let reach = radius.ceil() as i64;
+    let (width, height) = (width as i64, height as i64);
+
+    let mut offsets = Vec::new();
+    for dy in -reach..=reach {
+        for dx in -reach..=reach {
+            let coverage = (radius + 0.5 - ((dx * dx + dy * dy) as f32).sqrt()).clamp(0.0, 1.0);
+            if coverage > 0.0 {
+                offsets.push((dx, dy, coverage));
+            }
+        }
+    }
+
+    (0..height)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let offsets = &offsets;
+
+            (0..width).map(move |x| {
+                let mut value: f32 = 0.0;
+                for (dx, dy, coverage) in offsets {
+                    let (sx, sy) = (x + dx, y + dy);
+                    if sx < 0 || sy < 0 || sx >= width || sy >= height {
+                        continue;
+                    }
+
+                    value = value.max(mask[(sy * width + sx) as usize] * coverage);
+                }
+
+                value
+            })
+        })
+        .collect()
+}
+
+// Three box blurs approximate a gaussian blur with the radius as its standard deviation
+fn blur(mask: &[f32], width: u32, height: u32, radius: f32) -> Vec<f32> {
+    let radius = radius.round() as i64;
+    if radius < 1 {
+        return mask.to_vec();
+    }
+
+    let (width, height) = (width as i64, height as i64);
+    let mut mask = mask.to_vec();
+
+    let box_blur = |mask: &[f32], horizontal: bool| -> Vec<f32> {
+        let (length, lines) = if horizontal {
+            (width, height)
+        } else {
+            (height, width)
+        };
+        let index = |line: i64, position: i64| {
+            if horizontal {
+                (line * width + position) as usize
+            } else {
+                (position * width + line) as usize
+            }
+        };
+
+        let mut output = vec![0.0; mask.len()];
+        for line in 0..lines {
+            for position in 0..length {
+                let start = (position - radius).max(0);
+                let end = (position + radius).min(length - 1);
+
+                let sum: f32 = (start..=end).map(|other| mask[index(line, other)]).sum();
+                output[index(line, position)] = sum / (radius * 2 + 1) as f32;
+            }
+        }
+
+        output
+    };
+
+    for _ in 0..3 {
+        mask = box_blur(&mask, true);
+        mask = box_blur(&mask, false);
+    }
+
+    mask
+}
+
+fn shift(mask: &[f32], width: u32, height: u32, offset: (i64, i64)) -> Vec<f32> {
+    let (width, height) = (width as i64, height as i64);
+    let mut output = vec![0.0; mask.len()];
+
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = (x - offset.0, y - offset.1);
+            if sx >= 0 && sy >= 0 && sx < width && sy < height {
+                output[(y * width + x) as usize] = mask[(sy * width + sx) as usize];
+            }
+        }
+    }
+
+    output
+}
+
+pub fn apply_effects(image: RgbaImage, effects: &[Effect]) -> RgbaImage {
+    let mut image = image;
+
+    for effect in effects {
+        let (width, height) = image.dimensions();
+        let scale = width as f64;
+
+        match effect {
+            Effect::Padding(amount) => image = padding(&image, *amount),
+            Effect::Outline {
+                width: outline,
+                color,
+            } => {
+                let mask = dilate(&alpha_mask(&image), width, height, (outline * scale) as f32);
+                composite_under(&mut image, &mask, *color);
+            }
+            Effect::Shadow {
+                offset,
+                blur: radius,
+                color,
+            } => {
+                let offset = (
+                    (offset.0 * scale).round() as i64,
+                    (offset.1 * scale).round() as i64,
+                );
+
+                let mask = shift(&alpha_mask(&image), width, height, offset);
+                let mask = blur(&mask, width, height, (radius * scale) as f32);
+                composite_under(&mut image, &mask, *color);
+            }
+        }
+    }
+
+    image
+}
@@ -0,0 +1,32 @@
+use image::RgbaImage;
+use png::{BitDepth, ColorType, Encoder};
+
+// A single frame is written as a plain PNG
+pub fn encode(frames: &[(RgbaImage, u32)]) -> Vec<u8> {
+    let (width, height) = frames[0].0.dimensions();
+    let animated = frames.len() > 1;
+
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut buffer, width, height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+
+        if animated {
+            encoder.set_animated(frames.len() as u32, 0).unwrap();
+        }
+
+        let mut writer = encoder.write_header().unwrap();
+        for (frame, duration) in frames {
+            if animated {
+                writer.set_frame_delay(*duration as u16, 1000).unwrap();
+            }
+
+            writer.write_image_data(frame.as_raw()).unwrap();
+        }
+
+        writer.finish().unwrap();
+    }
+
+    buffer
+}
@@ -1,9 +1,32 @@
 use image::RgbaImage;
-use ravif::{Encoder, Img, RGBA8};
+use ravif::{AlphaColorMode, ColorSpace, Encoder, Img, RGBA8};
 use std::mem::transmute;
 
-pub fn encode(rgba: &RgbaImage, quality: f32, speed: u8) -> Vec<u8> {
-    let encoder = Encoder::new().with_quality(quality).with_speed(speed);
+use super::{AvifColorSpace, AvifOptions};
+
+pub fn encode(rgba: &RgbaImage, options: &AvifOptions) -> Vec<u8> {
+    let encoder = Encoder::new()
+        .with_speed(options.speed)
+        .with_depth(options.depth)
+        .with_num_threads(options.threads);
+
+    // Lossless needs the highest quality without a color transform,
+    // and has to keep the color of transparent pixels
+    let encoder = if options.lossless {
+        encoder
+            .with_quality(100.0)
+            .with_alpha_quality(100.0)
+            .with_internal_color_space(ColorSpace::RGB)
+            .with_alpha_color_mode(AlphaColorMode::UnassociatedDirty)
+    } else {
+        encoder
+            .with_quality(options.quality)
+            .with_alpha_quality(options.alpha_quality)
+            .with_internal_color_space(match options.color_space {
+                AvifColorSpace::YCbCr => ColorSpace::YCbCr,
+                AvifColorSpace::Rgb => ColorSpace::RGB,
+            })
+    };
 
     let img = Img::new(
         unsafe { transmute::<&[u8], &[RGBA8]>(rgba.as_raw()) },
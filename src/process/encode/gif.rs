@@ -0,0 +1,27 @@
+use gif::{DisposalMethod, Encoder, Frame, Repeat};
+use image::RgbaImage;
+
+pub fn encode(frames: &[(RgbaImage, u32)]) -> Vec<u8> {
+    let (width, height) = frames[0].0.dimensions();
+
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut buffer, width as u16, height as u16, &[]).unwrap();
+
+        if frames.len() > 1 {
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+        }
+
+        for (image, duration) in frames {
+            let mut pixels = image.as_raw().clone();
+
+            let mut frame = Frame::from_rgba_speed(width as u16, height as u16, &mut pixels, 10);
+            frame.delay = ((duration + 5) / 10) as u16; // centiseconds
+            frame.dispose = DisposalMethod::Background;
+
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+
+    buffer
+}
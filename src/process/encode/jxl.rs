@@ -0,0 +1,59 @@
+use image::RgbaImage;
+use jxl_encoder::{LosslessConfig, LossyConfig, PixelLayout};
+use jxl_oxide::JxlImage;
+
+pub fn encode(rgba: &RgbaImage, lossless: bool, distance: f32, effort: u8) -> Vec<u8> {
+    let (width, height) = rgba.dimensions();
+
+    let encoded = if lossless {
+        LosslessConfig::new().with_effort(effort).encode(
+            rgba.as_raw(),
+            width,
+            height,
+            PixelLayout::Rgba8,
+        )
+    } else {
+        LossyConfig::new(distance).with_effort(effort).encode(
+            rgba.as_raw(),
+            width,
+            height,
+            PixelLayout::Rgba8,
+        )
+    };
+
+    match encoded {
+        Ok(encoded) => encoded,
+        Err(err) => panic!(
+            "Failed to encode JPEG XL (lossless: {}, distance: {}, effort: {}): {}",
+            lossless, distance, effort, err
+        ),
+    }
+}
+
+pub fn decode(data: &[u8]) -> RgbaImage {
+    let image = match JxlImage::builder().read(data) {
+        Ok(image) => image,
+        Err(err) => panic!("Failed to decode JPEG XL: {}", err),
+    };
+    let render = match image.render_frame(0) {
+        Ok(render) => render,
+        Err(err) => panic!("Failed to decode JPEG XL: {}", err),
+    };
+
+    let mut stream = render.stream();
+    let (width, height, channels) = (stream.width(), stream.height(), stream.channels());
+    let mut buffer = vec![0u8; (width * height * channels) as usize];
+    stream.write_to_buffer(&mut buffer);
+
+    // Opaque images can be encoded without an alpha channel
+    let buffer = match channels {
+        4 => buffer,
+        3 => buffer
+            .chunks(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect(),
+        _ => panic!("Failed to decode JPEG XL: unexpected {} channels", channels),
+    };
+
+    RgbaImage::from_raw(width, height, buffer).unwrap()
+}
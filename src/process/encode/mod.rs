@@ -1,11 +1,16 @@
 use core::num::NonZeroU8;
-use image::RgbaImage;
-use oxipng::Deflaters;
+use image::{ImageFormat, RgbaImage};
+use oxipng::{Deflaters, Options, RowFilter, StripChunks};
 
+pub mod apng;
 pub mod avif;
+pub mod gif;
+pub mod jxl;
 pub mod png_image;
 pub mod png_oxipng;
+pub mod png_quantized;
 pub mod webp;
+pub mod webp_animated;
 
 #[derive(Clone, Debug)]
 pub enum OxiPngMode {
@@ -13,46 +18,212 @@ pub enum OxiPngMode {
     Zopfli(u8),      // 0-15
 }
 
+impl OxiPngMode {
+    pub fn to_deflater(&self) -> Deflaters {
+        match self {
+            OxiPngMode::Libdeflater(compression) => Deflaters::Libdeflater {
+                compression: *compression,
+            },
+            OxiPngMode::Zopfli(iterations) => Deflaters::Zopfli {
+                iterations: NonZeroU8::new(*iterations).unwrap(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OxiPngOptions {
+    pub preset: u8,                      // 0-6
+    pub filters: Option<Vec<RowFilter>>, // the preset's filters if not set
+    pub optimize_alpha: bool,
+    pub bit_depth_reduction: bool,
+    pub color_type_reduction: bool,
+    pub strip: StripChunks,
+}
+
+impl Default for OxiPngOptions {
+    fn default() -> Self {
+        Self {
+            preset: 2,
+            filters: None,
+            optimize_alpha: false,
+            bit_depth_reduction: true,
+            color_type_reduction: true,
+            strip: StripChunks::None,
+        }
+    }
+}
+
+impl OxiPngOptions {
+    // The deflater always overrides the one picked by the preset
+    pub fn to_options(&self, mode: &OxiPngMode) -> Options {
+        let mut options = Options::from_preset(self.preset);
+
+        options.deflate = mode.to_deflater();
+        if let Some(filters) = &self.filters {
+            options.filter = filters.iter().copied().collect();
+        }
+        options.optimize_alpha = self.optimize_alpha;
+        options.bit_depth_reduction = self.bit_depth_reduction;
+        options.color_type_reduction = self.color_type_reduction;
+        options.palette_reduction = self.color_type_reduction;
+        options.grayscale_reduction = self.color_type_reduction;
+        options.strip = self.strip.clone();
+
+        options
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AvifColorSpace {
+    YCbCr,
+    Rgb,
+}
+
+#[derive(Clone, Debug)]
+pub struct AvifOptions {
+    pub lossless: bool,
+    pub quality: f32,       // 100.0-1.0
+    pub alpha_quality: f32, // 100.0-1.0
+    pub speed: u8,          // 1-10
+    pub depth: Option<u8>,  // 8 or 10, picked by the encoder if not set
+    pub color_space: AvifColorSpace,
+    pub threads: Option<usize>,
+}
+
 #[derive(Clone, Debug)]
 pub enum EncodeTarget {
     PngImage,
-    PngOxipng(OxiPngMode),
-    Avif {
-        quality: f32, // 100.0-0.0
-        speed: u8,    // 1-10
+    PngOxipng {
+        mode: OxiPngMode,
+        options: OxiPngOptions,
+    },
+    PngQuantized {
+        colors: u16, // 2-256
+        dither: bool,
+        mode: OxiPngMode,
+        options: OxiPngOptions,
+    },
+    Avif(AvifOptions),
+    Webp {
+        lossless: bool,
+        quality: f32, // 0.0-100.0, compression effort when lossless
+        method: u8,   // 0-6
     },
-    Webp,
+    Jxl {
+        lossless: bool,
+        distance: f32, // 0.1-25.0, ignored when lossless
+        effort: u8,    // 1-10, 3-10 when lossy
+    },
+    Apng,
+    WebpAnimated {
+        lossless: bool,
+        quality: f32,
+        method: u8,
+    },
+    Gif,
 }
 
 impl EncodeTarget {
     pub fn to_extension(&self) -> &'static str {
         match self {
             EncodeTarget::PngImage => "png",
-            EncodeTarget::PngOxipng(_) => "png",
-            EncodeTarget::Avif { .. } => "avif",
-            EncodeTarget::Webp => "webp",
+            EncodeTarget::PngOxipng { .. } => "png",
+            EncodeTarget::PngQuantized { .. } => "png",
+            EncodeTarget::Avif(_) => "avif",
+            EncodeTarget::Webp { .. } => "webp",
+            EncodeTarget::Jxl { .. } => "jxl",
+            EncodeTarget::Apng => "png",
+            EncodeTarget::WebpAnimated { .. } => "webp",
+            EncodeTarget::Gif => "gif",
+        }
+    }
+
+    // Lossless formats reproduce the raster exactly
+    pub fn is_lossless(&self) -> bool {
+        match self {
+            EncodeTarget::PngImage | EncodeTarget::PngOxipng { .. } | EncodeTarget::Apng => true,
+            EncodeTarget::Avif(options) => options.lossless,
+            EncodeTarget::Webp { lossless, .. }
+            | EncodeTarget::WebpAnimated { lossless, .. }
+            | EncodeTarget::Jxl { lossless, .. } => *lossless,
+            EncodeTarget::PngQuantized { .. } | EncodeTarget::Gif => false,
         }
     }
+
+    pub fn can_decode(&self) -> bool {
+        matches!(
+            self,
+            EncodeTarget::PngImage
+                | EncodeTarget::PngOxipng { .. }
+                | EncodeTarget::PngQuantized { .. }
+                | EncodeTarget::Webp { .. }
+                | EncodeTarget::Jxl { .. }
+        )
+    }
+
+    pub fn is_animated(&self) -> bool {
+        matches!(
+            self,
+            EncodeTarget::Apng | EncodeTarget::WebpAnimated { .. } | EncodeTarget::Gif
+        )
+    }
 }
 
 pub fn encode_raster(raster: &RgbaImage, target: &EncodeTarget) -> Vec<u8> {
     match target {
-        EncodeTarget::PngOxipng(oxipng_mode) => match oxipng_mode {
-            OxiPngMode::Libdeflater(compression) => png_oxipng::encode(
-                &raster,
-                Deflaters::Libdeflater {
-                    compression: *compression,
-                },
-            ),
-            OxiPngMode::Zopfli(iterations) => png_oxipng::encode(
-                &raster,
-                Deflaters::Zopfli {
-                    iterations: NonZeroU8::new(*iterations).unwrap(),
-                },
-            ),
-        },
-        EncodeTarget::PngImage => png_image::encode(&raster),
-        EncodeTarget::Avif { quality, speed } => avif::encode(&raster, *quality, *speed),
-        EncodeTarget::Webp => webp::encode(&raster),
+        EncodeTarget::PngOxipng { mode, options } => {
+            png_oxipng::encode(raster, &options.to_options(mode))
+        }
+        EncodeTarget::PngQuantized {
+            colors,
+            dither,
+            mode,
+            options,
+        } => png_quantized::encode(raster, *colors, *dither, &options.to_options(mode)),
+        EncodeTarget::PngImage => png_image::encode(raster),
+        EncodeTarget::Avif(options) => avif::encode(raster, options),
+        EncodeTarget::Webp {
+            lossless,
+            quality,
+            method,
+        } => webp::encode(raster, *lossless, *quality, *method),
+        EncodeTarget::Jxl {
+            lossless,
+            distance,
+            effort,
+        } => jxl::encode(raster, *lossless, *distance, *effort),
+        EncodeTarget::Apng | EncodeTarget::WebpAnimated { .. } | EncodeTarget::Gif => {
+            encode_frames(&[(raster.clone(), 0)], target)
+        }
+    }
+}
+
+pub fn decode_raster(data: &[u8], target: &EncodeTarget) -> Option<RgbaImage> {
+    match target {
+        EncodeTarget::PngImage
+        | EncodeTarget::PngOxipng { .. }
+        | EncodeTarget::PngQuantized { .. } => Some(
+            image::load_from_memory_with_format(data, ImageFormat::Png)
+                .unwrap()
+                .to_rgba8(),
+        ),
+        EncodeTarget::Webp { .. } => Some(webp::decode(data)),
+        EncodeTarget::Jxl { .. } => Some(jxl::decode(data)),
+        _ => None,
+    }
+}
+
+// Frames with their durations in milliseconds
+pub fn encode_frames(frames: &[(RgbaImage, u32)], target: &EncodeTarget) -> Vec<u8> {
+    match target {
+        EncodeTarget::Apng => apng::encode(frames),
+        EncodeTarget::WebpAnimated {
+            lossless,
+            quality,
+            method,
+        } => webp_animated::encode(frames, *lossless, *quality, *method),
+        EncodeTarget::Gif => gif::encode(frames),
+        _ => encode_raster(&frames[0].0, target),
     }
 }
@@ -1,7 +1,7 @@
 use image::{codecs::png::PngEncoder, ImageEncoder, RgbaImage};
-use oxipng::{optimize_from_memory, Deflaters, Options};
+use oxipng::{optimize_from_memory, Options};
 
-pub fn encode(rgba: &RgbaImage, deflater: Deflaters) -> Vec<u8> {
+pub fn encode(rgba: &RgbaImage, options: &Options) -> Vec<u8> {
     let mut buffer = Vec::new();
 
     let encoder = PngEncoder::new(&mut buffer);
@@ -9,10 +9,5 @@ pub fn encode(rgba: &RgbaImage, deflater: Deflaters) -> Vec<u8> {
         .write_image(&rgba, rgba.width(), rgba.height(), image::ColorType::Rgba8)
         .unwrap();
 
-    let oxipng_options = Options {
-        deflate: deflater,
-        ..Default::default()
-    };
-
-    optimize_from_memory(&buffer, &oxipng_options).unwrap()
+    optimize_from_memory(&buffer, options).unwrap()
 }
@@ -0,0 +1,138 @@
+use color_quant::NeuQuant;
+use image::RgbaImage;
+use oxipng::{optimize_from_memory, Options};
+use png::{BitDepth, ColorType, Encoder};
+use std::collections::HashMap;
+
+// Returns the palette and an index for every pixel
+fn quantize(rgba: &RgbaImage, colors: usize, dither: bool) -> (Vec<[u8; 4]>, Vec<u8>) {
+    // Fully transparent pixels all share one palette entry
+    let pixels = rgba
+        .pixels()
+        .map(|pixel| if pixel[3] == 0 { [0; 4] } else { pixel.0 })
+        .collect::<Vec<_>>();
+
+    // Images with few enough colors keep them exactly
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    for pixel in &pixels {
+        if !lookup.contains_key(pixel) {
+            if palette.len() == colors {
+                break;
+            }
+
+            lookup.insert(*pixel, palette.len() as u8);
+            palette.push(*pixel);
+        }
+    }
+
+    if pixels.iter().all(|pixel| lookup.contains_key(pixel)) {
+        let indices = pixels.iter().map(|pixel| lookup[pixel]).collect();
+        return (palette, indices);
+    }
+
+    let transparent = pixels.iter().any(|pixel| pixel[3] == 0);
+    let reserved = transparent as usize;
+
+    let visible = pixels
+        .iter()
+        .filter(|pixel| pixel[3] != 0)
+        .flatten()
+        .copied()
+        .collect::<Vec<_>>();
+    let quantizer = NeuQuant::new(10, colors - reserved, &visible);
+
+    let mut palette = Vec::new();
+    if transparent {
+        palette.push([0; 4]);
+    }
+    palette.extend(
+        quantizer
+            .color_map_rgba()
+            .chunks(4)
+            .map(|color| [color[0], color[1], color[2], color[3]]),
+    );
+
+    let width = rgba.width() as usize;
+    let mut errors = vec![[0.0f32; 4]; pixels.len()];
+
+    let indices = pixels
+        .iter()
+        .enumerate()
+        .map(|(index, pixel)| {
+            if pixel[3] == 0 {
+                return 0;
+            }
+
+            let target = if dither {
+                let mut target = [0u8; 4];
+                for channel in 0..4 {
+                    target[channel] = (pixel[channel] as f32 + errors[index][channel])
+                        .round()
+                        .clamp(0.0, 255.0) as u8;
+                }
+                target
+            } else {
+                *pixel
+            };
+
+            let palette_index = quantizer.index_of(&target) + reserved;
+
+            // Floyd-Steinberg, transparent pixels don't take any error
+            if dither {
+                let chosen = palette[palette_index];
+                let (x, y) = (index % width, index / width);
+
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let (nx, ny) = (x as isize + dx, y + dy);
+                    if nx < 0 || nx as usize >= width || ny * width >= pixels.len() {
+                        return;
+                    }
+
+                    let neighbour = ny * width + nx as usize;
+                    if pixels[neighbour][3] == 0 {
+                        return;
+                    }
+
+                    for channel in 0..4 {
+                        errors[neighbour][channel] +=
+                            (target[channel] as f32 - chosen[channel] as f32) * weight;
+                    }
+                };
+
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+
+            palette_index as u8
+        })
+        .collect();
+
+    (palette, indices)
+}
+
+pub fn encode(rgba: &RgbaImage, colors: u16, dither: bool, options: &Options) -> Vec<u8> {
+    let (palette, indices) = quantize(rgba, colors as usize, dither);
+
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = Encoder::new(&mut buffer, rgba.width(), rgba.height());
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_palette(
+            palette
+                .iter()
+                .flat_map(|color| [color[0], color[1], color[2]])
+                .collect::<Vec<_>>(),
+        );
+        encoder.set_trns(palette.iter().map(|color| color[3]).collect::<Vec<_>>());
+
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&indices).unwrap();
+        writer.finish().unwrap();
+    }
+
+    optimize_from_memory(&buffer, options).unwrap()
+}
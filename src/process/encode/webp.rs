@@ -1,17 +1,102 @@
-use image::{codecs::webp::WebPEncoder, RgbaImage};
-
-pub fn encode(rgba: &RgbaImage) -> Vec<u8> {
-    let mut buffer = Vec::new();
-    let encoder = WebPEncoder::new(&mut buffer);
-
-    encoder
-        .encode(
-            rgba.as_raw(),
-            rgba.width(),
-            rgba.height(),
-            image::ColorType::Rgba8,
-        )
-        .unwrap();
-
-    buffer
+use image::RgbaImage;
+use libwebp_sys::*;
+use std::{ffi::c_void, mem::MaybeUninit, slice};
+
+// With lossless encoding, quality is the compression effort
+pub fn config(lossless: bool, quality: f32, method: u8) -> WebPConfig {
+    unsafe {
+        let mut config = MaybeUninit::<WebPConfig>::uninit();
+        if WebPConfigInitInternal(
+            config.as_mut_ptr(),
+            WebPPreset::WEBP_PRESET_DEFAULT,
+            quality,
+            WEBP_ENCODER_ABI_VERSION,
+        ) == 0
+        {
+            panic!("Failed to initialize WebP config");
+        }
+        let mut config = config.assume_init();
+
+        config.lossless = lossless as i32;
+        config.method = method as i32;
+        // Keep the color of transparent pixels, so lossless output is pixel-exact
+        config.exact = lossless as i32;
+
+        if WebPValidateConfig(&config) == 0 {
+            panic!(
+                "Invalid WebP config (lossless: {}, quality: {}, method: {})",
+                lossless, quality, method
+            );
+        }
+
+        config
+    }
+}
+
+pub fn picture(rgba: &RgbaImage) -> WebPPicture {
+    unsafe {
+        let mut picture = MaybeUninit::<WebPPicture>::uninit();
+        if WebPPictureInitInternal(picture.as_mut_ptr(), WEBP_ENCODER_ABI_VERSION) == 0 {
+            panic!("Failed to initialize WebP picture");
+        }
+        let mut picture = picture.assume_init();
+
+        picture.use_argb = 1;
+        picture.width = rgba.width() as i32;
+        picture.height = rgba.height() as i32;
+
+        if WebPPictureImportRGBA(
+            &mut picture,
+            rgba.as_raw().as_ptr(),
+            rgba.width() as i32 * 4,
+        ) == 0
+        {
+            panic!("Failed to import image into WebP picture");
+        }
+
+        picture
+    }
+}
+
+pub fn encode(rgba: &RgbaImage, lossless: bool, quality: f32, method: u8) -> Vec<u8> {
+    let config = config(lossless, quality, method);
+    let mut picture = picture(rgba);
+
+    unsafe {
+        let mut writer = MaybeUninit::<WebPMemoryWriter>::uninit();
+        WebPMemoryWriterInit(writer.as_mut_ptr());
+        let mut writer = writer.assume_init();
+
+        picture.writer = Some(WebPMemoryWrite);
+        picture.custom_ptr = &mut writer as *mut WebPMemoryWriter as *mut c_void;
+
+        let encoded = WebPEncode(&config, &mut picture);
+        let error = picture.error_code;
+        WebPPictureFree(&mut picture);
+
+        if encoded == 0 {
+            WebPMemoryWriterClear(&mut writer);
+            panic!("Failed to encode WebP: {:?}", error);
+        }
+
+        let buffer = slice::from_raw_parts(writer.mem, writer.size).to_vec();
+        WebPMemoryWriterClear(&mut writer);
+
+        buffer
+    }
+}
+
+pub fn decode(data: &[u8]) -> RgbaImage {
+    unsafe {
+        let (mut width, mut height) = (0, 0);
+        let pixels = WebPDecodeRGBA(data.as_ptr(), data.len(), &mut width, &mut height);
+        if pixels.is_null() {
+            panic!("Failed to decode WebP");
+        }
+
+        let buffer = slice::from_raw_parts(pixels, (width * height * 4) as usize).to_vec();
+        WebPFree(pixels as *mut c_void);
+
+        RgbaImage::from_raw(width as u32, height as u32, buffer).unwrap()
+    }
 }
@@ -0,0 +1,60 @@
+use image::RgbaImage;
+use libwebp_sys::*;
+use std::{ffi::CStr, mem::MaybeUninit, slice};
+
+use super::webp::{config, picture};
+
+pub fn encode(frames: &[(RgbaImage, u32)], lossless: bool, quality: f32, method: u8) -> Vec<u8> {
+    let (width, height) = frames[0].0.dimensions();
+
+    unsafe {
+        let mut options = MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+        if WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), WEBP_MUX_ABI_VERSION) == 0 {
+            panic!("Failed to initialize animated WebP encoder options");
+        }
+        let options = options.assume_init();
+
+        let encoder =
+            WebPAnimEncoderNewInternal(width as i32, height as i32, &options, WEBP_MUX_ABI_VERSION);
+        if encoder.is_null() {
+            panic!("Failed to create animated WebP encoder");
+        }
+
+        let config = config(lossless, quality, method);
+
+        let error = |encoder: *mut WebPAnimEncoder| {
+            CStr::from_ptr(WebPAnimEncoderGetError(encoder))
+                .to_string_lossy()
+                .to_string()
+        };
+
+        let mut timestamp = 0;
+        for (image, duration) in frames {
+            let mut picture = picture(image);
+
+            let added = WebPAnimEncoderAdd(encoder, &mut picture, timestamp, &config);
+            WebPPictureFree(&mut picture);
+
+            if added == 0 {
+                panic!("Failed to add frame to animated WebP: {}", error(encoder));
+            }
+
+            timestamp += *duration as i32;
+        }
+
+        // A final empty frame sets the duration of the last one
+        WebPAnimEncoderAdd(encoder, std::ptr::null_mut(), timestamp, std::ptr::null());
+
+        let mut data = WebPData::default();
+        if WebPAnimEncoderAssemble(encoder, &mut data) == 0 {
+            panic!("Failed to assemble animated WebP: {}", error(encoder));
+        }
+
+        let buffer = slice::from_raw_parts(data.bytes, data.size).to_vec();
+
+        WebPDataClear(&mut data);
+        WebPAnimEncoderDelete(encoder);
+
+        buffer
+    }
+}
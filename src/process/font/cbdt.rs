@@ -0,0 +1,133 @@
+use write_fonts::types::{GlyphId16, Tag};
+
+use super::{ColorTables, GlyphOrder, ASCENDER, DESCENDER, UNITS_PER_EM};
+use crate::process::EmojiEncoded;
+
+const CBLC_HEADER_SIZE: usize = 8;
+const BITMAP_SIZE_RECORD_SIZE: usize = 48;
+const INDEX_SUBTABLE_RECORD_SIZE: usize = 8;
+
+// Glyph image format 17: small metrics followed by PNG data
+const IMAGE_FORMAT_PNG_SMALL_METRICS: u16 = 17;
+// Index subtable format 1: 32-bit offsets for a contiguous range of glyphs
+const INDEX_FORMAT_OFFSETS: u16 = 1;
+
+fn scale(value: i16, size: u32) -> i8 {
+    (value as f64 * size as f64 / UNITS_PER_EM as f64).round() as i8
+}
+
+fn line_metrics(size: u32) -> [u8; 12] {
+    let ascender = scale(ASCENDER, size);
+    let descender = scale(DESCENDER, size);
+
+    [
+        ascender as u8,
+        descender as u8,
+        size as u8, // widthMax
+        1,          // caretSlopeNumerator
+        0,          // caretSlopeDenominator
+        0,          // caretOffset
+        0,          // minOriginSB
+        0,          // minAdvanceSB
+        ascender as u8,
+        descender as u8,
+        0,
+        0,
+    ]
+}
+
+pub fn build(
+    emojis: &[EmojiEncoded],
+    order: &GlyphOrder,
+    sizes: &[u32],
+    target_name: &str,
+) -> ColorTables {
+    let mut glyphs = order.emojis.clone();
+    glyphs.sort_by_key(|(glyph, _)| *glyph);
+
+    let (first_glyph, last_glyph) = match (glyphs.first(), glyphs.last()) {
+        (Some((first, _)), Some((last, _))) => (*first, *last),
+        _ => panic!("Font target '{}' does not contain any emojis", target_name),
+    };
+
+    let mut cbdt = Vec::new();
+    cbdt.extend_from_slice(&3u16.to_be_bytes());
+    cbdt.extend_from_slice(&0u16.to_be_bytes());
+
+    let mut size_records = Vec::new();
+    let mut index_tables = Vec::new();
+
+    let index_tables_offset = CBLC_HEADER_SIZE + BITMAP_SIZE_RECORD_SIZE * sizes.len();
+
+    for (strike, size) in sizes.iter().enumerate() {
+        // Glyph data for this strike, one contiguous block in CBDT
+        let image_data_offset = cbdt.len() as u32;
+        let mut offsets = Vec::new();
+        let mut glyph_iter = glyphs.iter().peekable();
+
+        for glyph in first_glyph.to_u16()..=last_glyph.to_u16() {
+            offsets.push(cbdt.len() as u32 - image_data_offset);
+
+            let index = match glyph_iter.peek() {
+                Some((next, index)) if *next == GlyphId16::new(glyph) => *index,
+                _ => continue,
+            };
+            glyph_iter.next();
+
+            let png = &emojis[index].strikes[strike];
+
+            cbdt.push(*size as u8); // height
+            cbdt.push(*size as u8); // width
+            cbdt.push(0); // bearingX
+            cbdt.push(scale(ASCENDER, *size) as u8); // bearingY
+            cbdt.push(*size as u8); // advance
+            cbdt.extend_from_slice(&(png.len() as u32).to_be_bytes());
+            cbdt.extend_from_slice(png);
+        }
+        offsets.push(cbdt.len() as u32 - image_data_offset);
+
+        // Index subtable array with a single subtable covering every emoji glyph
+        let index_table_start = index_tables.len();
+        let mut index_table = Vec::new();
+        index_table.extend_from_slice(&first_glyph.to_u16().to_be_bytes());
+        index_table.extend_from_slice(&last_glyph.to_u16().to_be_bytes());
+        index_table.extend_from_slice(&(INDEX_SUBTABLE_RECORD_SIZE as u32).to_be_bytes());
+
+        index_table.extend_from_slice(&INDEX_FORMAT_OFFSETS.to_be_bytes());
+        index_table.extend_from_slice(&IMAGE_FORMAT_PNG_SMALL_METRICS.to_be_bytes());
+        index_table.extend_from_slice(&image_data_offset.to_be_bytes());
+        for offset in offsets {
+            index_table.extend_from_slice(&offset.to_be_bytes());
+        }
+
+        index_tables.extend_from_slice(&index_table);
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&((index_tables_offset + index_table_start) as u32).to_be_bytes());
+        record.extend_from_slice(&(index_table.len() as u32).to_be_bytes());
+        record.extend_from_slice(&1u32.to_be_bytes()); // numberOfIndexSubTables
+        record.extend_from_slice(&0u32.to_be_bytes()); // colorRef
+        record.extend_from_slice(&line_metrics(*size));
+        record.extend_from_slice(&line_metrics(*size));
+        record.extend_from_slice(&first_glyph.to_u16().to_be_bytes());
+        record.extend_from_slice(&last_glyph.to_u16().to_be_bytes());
+        record.push(*size as u8); // ppemX
+        record.push(*size as u8); // ppemY
+        record.push(32); // bitDepth
+        record.push(1); // flags: horizontal metrics
+
+        size_records.extend_from_slice(&record);
+    }
+
+    let mut cblc = Vec::new();
+    cblc.extend_from_slice(&3u16.to_be_bytes());
+    cblc.extend_from_slice(&0u16.to_be_bytes());
+    cblc.extend_from_slice(&(sizes.len() as u32).to_be_bytes());
+    cblc.extend_from_slice(&size_records);
+    cblc.extend_from_slice(&index_tables);
+
+    ColorTables {
+        outlines: Vec::new(),
+        tables: vec![(Tag::new(b"CBDT"), cbdt), (Tag::new(b"CBLC"), cblc)],
+    }
+}
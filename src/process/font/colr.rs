@@ -0,0 +1,693 @@
+use kurbo::{Affine, BezPath, CubicBez, PathEl, Point};
+use rayon::prelude::*;
+use resvg::{
+    tiny_skia::{self, PathBuilder, StrokeDash},
+    usvg::{
+        self, Color, LineCap, LineJoin, NodeExt, NodeKind, Paint as SvgPaint, PathData,
+        PathSegment, SpreadMethod, Tree, TreeParsing, Units,
+    },
+};
+use std::collections::HashMap;
+use write_fonts::{
+    tables::{
+        colr::{
+            BaseGlyphList, BaseGlyphPaint, Clip, ClipBox, ClipBoxFormat1, ClipList, ColorLine,
+            ColorStop, Colr, CompositeMode, Extend, LayerList, Paint,
+        },
+        cpal::{ColorRecord, Cpal},
+        glyf::SimpleGlyph,
+    },
+    types::{F2Dot14, Fixed, GlyphId16},
+};
+
+use super::{compile_table, to_fword, ColorTables, GlyphOrder, ASCENDER, DESCENDER, UNITS_PER_EM};
+use crate::load::manifest::Emoji;
+use crate::process::EmojiEncoded;
+
+// Maximum distance between a cubic curve and its quadratic approximation, in font units
+const CURVE_ACCURACY: f64 = 0.5;
+
+#[derive(Clone)]
+struct GradientStop {
+    offset: f64,
+    color: Color,
+    alpha: f64,
+}
+
+enum Brush {
+    Solid {
+        color: Color,
+        alpha: f64,
+    },
+    LinearGradient {
+        stops: Vec<GradientStop>,
+        extend: Extend,
+        points: [Point; 3],
+    },
+    RadialGradient {
+        stops: Vec<GradientStop>,
+        extend: Extend,
+        start: (Point, f64),
+        end: (Point, f64),
+        transform: Option<Affine>,
+    },
+}
+
+// A paint tree for one emoji, independent of glyph ids and palette indices
+enum Layer {
+    Fill {
+        outline: SimpleGlyph,
+        brush: Brush,
+    },
+    Clip {
+        outline: SimpleGlyph,
+        layers: Vec<Layer>,
+    },
+    // Opacity applied once to the layers as a whole, like a group in the SVG
+    Group {
+        opacity: f64,
+        layers: Vec<Layer>,
+    },
+}
+
+fn to_affine(ts: &usvg::Transform) -> Affine {
+    Affine::new([ts.a, ts.b, ts.c, ts.d, ts.e, ts.f])
+}
+
+// Maps the SVG viewbox onto the em square, flipping the y axis
+fn viewbox_transform(tree: &Tree) -> Affine {
+    let rect = tree.view_box.rect;
+    let scale = UNITS_PER_EM as f64 / rect.width().max(rect.height());
+
+    let offset_x = (UNITS_PER_EM as f64 - rect.width() * scale) / 2.0;
+    let offset_y = (UNITS_PER_EM as f64 - rect.height() * scale) / 2.0;
+
+    Affine::translate((offset_x, ASCENDER as f64 - offset_y))
+        * Affine::scale_non_uniform(scale, -scale)
+        * Affine::translate((-rect.x(), -rect.y()))
+}
+
+fn to_bezpath(data: &PathData, ts: Affine) -> BezPath {
+    let mut path = BezPath::new();
+
+    for segment in data.segments() {
+        match segment {
+            PathSegment::MoveTo { x, y } => path.move_to(ts * Point::new(x, y)),
+            PathSegment::LineTo { x, y } => path.line_to(ts * Point::new(x, y)),
+            PathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => path.curve_to(
+                ts * Point::new(x1, y1),
+                ts * Point::new(x2, y2),
+                ts * Point::new(x, y),
+            ),
+            PathSegment::ClosePath => path.close_path(),
+        }
+    }
+
+    path
+}
+
+fn stroke_to_bezpath(data: &PathData, stroke: &usvg::Stroke, ts: Affine) -> Option<BezPath> {
+    let mut builder = PathBuilder::new();
+    for segment in data.segments() {
+        match segment {
+            PathSegment::MoveTo { x, y } => builder.move_to(x as f32, y as f32),
+            PathSegment::LineTo { x, y } => builder.line_to(x as f32, y as f32),
+            PathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => builder.cubic_to(
+                x1 as f32, y1 as f32, x2 as f32, y2 as f32, x as f32, y as f32,
+            ),
+            PathSegment::ClosePath => builder.close(),
+        }
+    }
+
+    let mut path = builder.finish()?;
+
+    if let Some(dasharray) = &stroke.dasharray {
+        let dash = StrokeDash::new(
+            dasharray.iter().map(|dash| *dash as f32).collect(),
+            stroke.dashoffset,
+        )?;
+        path = path.dash(&dash, 1.0)?;
+    }
+
+    let stroke = tiny_skia::Stroke {
+        width: stroke.width.get() as f32,
+        miter_limit: stroke.miterlimit.get() as f32,
+        line_cap: match stroke.linecap {
+            LineCap::Butt => tiny_skia::LineCap::Butt,
+            LineCap::Round => tiny_skia::LineCap::Round,
+            LineCap::Square => tiny_skia::LineCap::Square,
+        },
+        line_join: match stroke.linejoin {
+            LineJoin::Miter => tiny_skia::LineJoin::Miter,
+            LineJoin::Round => tiny_skia::LineJoin::Round,
+            LineJoin::Bevel => tiny_skia::LineJoin::Bevel,
+        },
+        dash: None,
+    };
+
+    // Flatten curves with enough precision for the final font size
+    let resolution_scale = ts.determinant().abs().sqrt() as f32;
+    let outline = path.stroke(&stroke, resolution_scale.max(1.0))?;
+
+    let mut bezpath = BezPath::new();
+    for segment in outline.segments() {
+        let point = |p: tiny_skia::Point| ts * Point::new(p.x as f64, p.y as f64);
+
+        match segment {
+            tiny_skia::PathSegment::MoveTo(p) => bezpath.move_to(point(p)),
+            tiny_skia::PathSegment::LineTo(p) => bezpath.line_to(point(p)),
+            tiny_skia::PathSegment::QuadTo(p1, p) => bezpath.quad_to(point(p1), point(p)),
+            tiny_skia::PathSegment::CubicTo(p1, p2, p) => {
+                bezpath.curve_to(point(p1), point(p2), point(p))
+            }
+            tiny_skia::PathSegment::Close => bezpath.close_path(),
+        }
+    }
+
+    Some(bezpath)
+}
+
+// TrueType outlines only support quadratic curves
+fn to_outline(path: &BezPath) -> Option<SimpleGlyph> {
+    let mut quadratic = BezPath::new();
+    let mut last = Point::ZERO;
+    let mut start = Point::ZERO;
+
+    for element in path.elements() {
+        match *element {
+            PathEl::MoveTo(p) => {
+                quadratic.move_to(p);
+                last = p;
+                start = p;
+            }
+            PathEl::LineTo(p) => {
+                quadratic.line_to(p);
+                last = p;
+            }
+            PathEl::QuadTo(p1, p) => {
+                quadratic.quad_to(p1, p);
+                last = p;
+            }
+            PathEl::CurveTo(p1, p2, p) => {
+                for (_, _, quad) in CubicBez::new(last, p1, p2, p).to_quads(CURVE_ACCURACY) {
+                    quadratic.quad_to(quad.p1, quad.p2);
+                }
+                last = p;
+            }
+            PathEl::ClosePath => {
+                quadratic.close_path();
+                last = start;
+            }
+        }
+    }
+
+    match SimpleGlyph::from_bezpath(&quadratic) {
+        Ok(glyph) if !glyph.contours.is_empty() => Some(glyph),
+        _ => None,
+    }
+}
+
+fn to_extend(spread_method: SpreadMethod) -> Extend {
+    match spread_method {
+        SpreadMethod::Pad => Extend::Pad,
+        SpreadMethod::Reflect => Extend::Reflect,
+        SpreadMethod::Repeat => Extend::Repeat,
+    }
+}
+
+fn to_stops(stops: &[usvg::Stop], opacity: f64) -> Vec<GradientStop> {
+    stops
+        .iter()
+        .map(|stop| GradientStop {
+            offset: stop.offset.get(),
+            color: stop.color,
+            alpha: stop.opacity.get() * opacity,
+        })
+        .collect()
+}
+
+fn to_brush(paint: &SvgPaint, opacity: f64, data: &PathData, ts: Affine) -> Option<Brush> {
+    // Gradient space to font space
+    let gradient_transform = |units: Units, transform: &usvg::Transform| {
+        let bbox = match units {
+            Units::ObjectBoundingBox => {
+                let bbox = data.bbox()?;
+                Affine::new([bbox.width(), 0.0, 0.0, bbox.height(), bbox.x(), bbox.y()])
+            }
+            Units::UserSpaceOnUse => Affine::IDENTITY,
+        };
+
+        Some(ts * bbox * to_affine(transform))
+    };
+
+    match paint {
+        SvgPaint::Color(color) => Some(Brush::Solid {
+            color: *color,
+            alpha: opacity,
+        }),
+        SvgPaint::LinearGradient(gradient) => {
+            let ts = gradient_transform(gradient.units, &gradient.transform)?;
+
+            let start = Point::new(gradient.x1, gradient.y1);
+            let end = Point::new(gradient.x2, gradient.y2);
+            let direction = end - start;
+            // Color lines are perpendicular to the gradient vector before transforming
+            let rotation = start + kurbo::Vec2::new(-direction.y, direction.x);
+
+            Some(Brush::LinearGradient {
+                stops: to_stops(&gradient.stops, opacity),
+                extend: to_extend(gradient.spread_method),
+                points: [ts * start, ts * end, ts * rotation],
+            })
+        }
+        SvgPaint::RadialGradient(gradient) => {
+            let ts = gradient_transform(gradient.units, &gradient.transform)?;
+
+            let scale = ts.determinant().abs().sqrt();
+            if scale == 0.0 {
+                return None;
+            }
+
+            let start = Point::new(gradient.fx, gradient.fy);
+            let end = Point::new(gradient.cx, gradient.cy);
+            let radius = gradient.r.get();
+
+            // Circles stay circles under similarity transforms, otherwise keep a transform
+            let [a, b, c, d, _, _] = ts.as_coeffs();
+            let similarity = ((a * a + b * b) / (scale * scale) - 1.0).abs() < 1e-3
+                && ((c * c + d * d) / (scale * scale) - 1.0).abs() < 1e-3
+                && ((a * c + b * d) / (scale * scale)).abs() < 1e-3;
+
+            let (start, end, transform) = if similarity {
+                (ts * start, ts * end, None)
+            } else {
+                let scaled = Affine::scale(scale);
+                (
+                    scaled * start,
+                    scaled * end,
+                    Some(ts * Affine::scale(1.0 / scale)),
+                )
+            };
+
+            Some(Brush::RadialGradient {
+                stops: to_stops(&gradient.stops, opacity),
+                extend: to_extend(gradient.spread_method),
+                start: (start, 0.0),
+                end: (end, radius * scale),
+                transform,
+            })
+        }
+        // Patterns cannot be represented in COLRv1
+        SvgPaint::Pattern(_) => None,
+    }
+}
+
+fn convert_clip_path(clip_path: &usvg::ClipPath, node: &usvg::Node, ts: Affine) -> BezPath {
+    let ts = match clip_path.units {
+        Units::ObjectBoundingBox => match node.calculate_bbox() {
+            Some(bbox) => {
+                ts * to_affine(&node.abs_transform()).inverse()
+                    * Affine::new([bbox.width(), 0.0, 0.0, bbox.height(), bbox.x(), bbox.y()])
+            }
+            None => ts,
+        },
+        Units::UserSpaceOnUse => ts,
+    } * to_affine(&clip_path.transform);
+
+    let mut outline = BezPath::new();
+    for node in clip_path.root.descendants() {
+        if let NodeKind::Path(ref path) = *node.borrow() {
+            let path_ts = to_affine(&node.abs_transform());
+            outline.extend(
+                to_bezpath(&path.data, ts * path_ts)
+                    .elements()
+                    .iter()
+                    .copied(),
+            );
+        }
+    }
+
+    outline
+}
+
+fn fade(brush: &mut Brush, opacity: f64) {
+    match brush {
+        Brush::Solid { alpha, .. } => *alpha *= opacity,
+        Brush::LinearGradient { stops, .. } | Brush::RadialGradient { stops, .. } => {
+            for stop in stops.iter_mut() {
+                stop.alpha *= opacity;
+            }
+        }
+    }
+}
+
+fn convert_node(node: &usvg::Node, ts: Affine) -> Vec<Layer> {
+    match *node.borrow() {
+        NodeKind::Group(ref group) => {
+            let ts = ts * to_affine(&group.transform);
+            let opacity = group.opacity.get();
+
+            let mut layers = node
+                .children()
+                .flat_map(|child| convert_node(&child, ts))
+                .collect::<Vec<_>>();
+
+            // Overlapping children of a translucent group must not show through each other,
+            // unless there is a single fill, which can't overlap itself
+            if opacity < 1.0 {
+                match layers.as_mut_slice() {
+                    [] => (),
+                    [Layer::Fill { brush, .. }] => fade(brush, opacity),
+                    _ => layers = vec![Layer::Group { opacity, layers }],
+                }
+            }
+
+            match &group.clip_path {
+                Some(clip_path) => {
+                    match to_outline(&convert_clip_path(clip_path, node, ts)) {
+                        Some(outline) => vec![Layer::Clip { outline, layers }],
+                        // Nothing is visible through an empty clip path
+                        None => vec![],
+                    }
+                }
+                None => layers,
+            }
+        }
+        NodeKind::Path(ref path) => {
+            if path.visibility != usvg::Visibility::Visible {
+                return vec![];
+            }
+
+            let ts = ts * to_affine(&path.transform);
+            let mut layers = Vec::new();
+
+            let fill = path.fill.as_ref().and_then(|fill| {
+                let outline = to_outline(&to_bezpath(&path.data, ts))?;
+                let brush = to_brush(&fill.paint, fill.opacity.get(), &path.data, ts)?;
+
+                Some(Layer::Fill { outline, brush })
+            });
+
+            let stroke = path.stroke.as_ref().and_then(|stroke| {
+                let outline = to_outline(&stroke_to_bezpath(&path.data, stroke, ts)?)?;
+                let brush = to_brush(&stroke.paint, stroke.opacity.get(), &path.data, ts)?;
+
+                Some(Layer::Fill { outline, brush })
+            });
+
+            match path.paint_order {
+                usvg::PaintOrder::FillAndStroke => layers.extend(fill.into_iter().chain(stroke)),
+                usvg::PaintOrder::StrokeAndFill => layers.extend(stroke.into_iter().chain(fill)),
+            }
+
+            layers
+        }
+        // Raster images and unconverted text cannot be represented in COLRv1
+        NodeKind::Image(_) | NodeKind::Text(_) => vec![],
+    }
+}
+
+fn convert_svg(svg: &str, name: &str) -> Vec<Layer> {
+    let tree = match Tree::from_str(svg, &Default::default()) {
+        Ok(tree) => tree,
+        Err(err) => panic!("Error parsing SVG of emoji '{}' for font: {}", name, err),
+    };
+
+    let ts = viewbox_transform(&tree);
+
+    tree.root
+        .children()
+        .flat_map(|child| convert_node(&child, ts))
+        .collect()
+}
+
+fn to_f2dot14(value: f64) -> F2Dot14 {
+    F2Dot14::from_f32(value.clamp(-2.0, 2.0) as f32)
+}
+
+#[derive(Default)]
+struct ColrBuilder {
+    palette: Vec<Color>,
+    palette_indices: HashMap<(u8, u8, u8), u16>,
+    outlines: Vec<SimpleGlyph>,
+    first_outline_glyph: u16,
+    layers: Vec<Paint>,
+    target_name: String,
+}
+
+impl ColrBuilder {
+    fn palette_index(&mut self, color: Color) -> u16 {
+        let key = (color.red, color.green, color.blue);
+
+        match self.palette_indices.get(&key) {
+            Some(index) => *index,
+            None => {
+                let index = self.palette.len() as u16;
+                self.palette.push(color);
+                self.palette_indices.insert(key, index);
+                index
+            }
+        }
+    }
+
+    // Outline glyphs follow the emoji glyphs, and a font can contain at most 65535 glyphs
+    fn outline_glyph(&mut self, outline: SimpleGlyph, emoji: &Emoji) -> GlyphId16 {
+        let glyph = match self
+            .first_outline_glyph
+            .checked_add(self.outlines.len() as u16)
+        {
+            Some(glyph) if glyph < u16::MAX => glyph,
+            _ => panic!(
+                "Font target '{}' needs more glyphs than a font can contain, reached at emoji '{}' in {:?}",
+                self.target_name, emoji.name, emoji.manifest_path
+            ),
+        };
+
+        self.outlines.push(outline);
+        GlyphId16::new(glyph)
+    }
+
+    fn color_line(&mut self, stops: &[GradientStop], extend: Extend) -> ColorLine {
+        let stops = stops
+            .iter()
+            .map(|stop| {
+                ColorStop::new(
+                    to_f2dot14(stop.offset),
+                    self.palette_index(stop.color),
+                    to_f2dot14(stop.alpha),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        ColorLine::new(extend, stops.len() as u16, stops)
+    }
+
+    fn brush_paint(&mut self, brush: &Brush) -> Paint {
+        match brush {
+            Brush::Solid { color, alpha } => {
+                Paint::solid(self.palette_index(*color), to_f2dot14(*alpha))
+            }
+            Brush::LinearGradient {
+                stops,
+                extend,
+                points,
+            } => Paint::linear_gradient(
+                self.color_line(stops, *extend),
+                to_fword(points[0].x).into(),
+                to_fword(points[0].y).into(),
+                to_fword(points[1].x).into(),
+                to_fword(points[1].y).into(),
+                to_fword(points[2].x).into(),
+                to_fword(points[2].y).into(),
+            ),
+            Brush::RadialGradient {
+                stops,
+                extend,
+                start,
+                end,
+                transform,
+            } => {
+                let gradient = Paint::radial_gradient(
+                    self.color_line(stops, *extend),
+                    to_fword(start.0.x).into(),
+                    to_fword(start.0.y).into(),
+                    (to_fword(start.1).max(0) as u16).into(),
+                    to_fword(end.0.x).into(),
+                    to_fword(end.0.y).into(),
+                    (to_fword(end.1).max(0) as u16).into(),
+                );
+
+                match transform {
+                    Some(transform) => {
+                        let [xx, yx, xy, yy, dx, dy] = transform.as_coeffs();
+                        Paint::transform(
+                            gradient,
+                            write_fonts::tables::colr::Affine2x3::new(
+                                Fixed::from_f64(xx),
+                                Fixed::from_f64(yx),
+                                Fixed::from_f64(xy),
+                                Fixed::from_f64(yy),
+                                Fixed::from_f64(dx),
+                                Fixed::from_f64(dy),
+                            ),
+                        )
+                    }
+                    None => gradient,
+                }
+            }
+        }
+    }
+
+    fn layer_paint(&mut self, layer: Layer, emoji: &Emoji) -> Paint {
+        match layer {
+            Layer::Fill { outline, brush } => {
+                let paint = self.brush_paint(&brush);
+                let glyph = self.outline_glyph(outline, emoji);
+                Paint::glyph(paint, glyph)
+            }
+            Layer::Clip { outline, layers } => {
+                let paint = self.layers_paint(layers, emoji);
+                let glyph = self.outline_glyph(outline, emoji);
+                Paint::glyph(paint, glyph)
+            }
+            // Keeps the layers where an opaque backdrop is, scaled by its alpha
+            Layer::Group { opacity, layers } => {
+                let paint = self.layers_paint(layers, emoji);
+                let backdrop =
+                    Paint::solid(self.palette_index(Color::black()), to_f2dot14(opacity));
+                Paint::composite(paint, CompositeMode::SrcIn, backdrop)
+            }
+        }
+    }
+
+    // PaintColrLayers can only reference 255 layers, so larger lists are nested
+    fn layers_paint(&mut self, layers: Vec<Layer>, emoji: &Emoji) -> Paint {
+        let mut paints = layers
+            .into_iter()
+            .map(|layer| self.layer_paint(layer, emoji))
+            .collect::<Vec<_>>();
+
+        if paints.len() == 1 {
+            return paints.pop().unwrap();
+        }
+
+        while paints.len() > u8::MAX as usize {
+            paints = paints
+                .chunks(u8::MAX as usize)
+                .map(|chunk| self.push_layers(chunk.to_vec()))
+                .collect();
+        }
+
+        self.push_layers(paints)
+    }
+
+    fn push_layers(&mut self, paints: Vec<Paint>) -> Paint {
+        let first_layer_index = self.layers.len() as u32;
+        let num_layers = paints.len() as u8;
+
+        self.layers.extend(paints);
+
+        Paint::colr_layers(num_layers, first_layer_index)
+    }
+}
+
+pub fn build(emojis: &[EmojiEncoded], order: &GlyphOrder, target_name: &str) -> ColorTables {
+    let converted = order
+        .emojis
+        .par_iter()
+        .map(|(glyph, index)| {
+            let emoji = &emojis[*index].emoji;
+            (
+                *glyph,
+                emoji,
+                convert_svg(&emoji.svg.as_ref().unwrap().0, &emoji.name),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let first_outline_glyph = match u16::try_from(order.len()) {
+        Ok(first_outline_glyph) => first_outline_glyph,
+        Err(_) => panic!(
+            "Font target '{}' needs more glyphs than a font can contain",
+            target_name
+        ),
+    };
+
+    let mut builder = ColrBuilder {
+        first_outline_glyph,
+        target_name: target_name.to_string(),
+        ..Default::default()
+    };
+
+    let mut base_glyphs = converted
+        .into_iter()
+        .map(|(glyph, emoji, layers)| {
+            BaseGlyphPaint::new(glyph, builder.layers_paint(layers, emoji))
+        })
+        .collect::<Vec<_>>();
+    base_glyphs.sort_by_key(|base_glyph| base_glyph.glyph_id);
+
+    let clips = base_glyphs
+        .iter()
+        .map(|base_glyph| {
+            Clip::new(
+                base_glyph.glyph_id,
+                base_glyph.glyph_id,
+                ClipBox::Format1(ClipBoxFormat1::new(
+                    0.into(),
+                    DESCENDER.into(),
+                    (UNITS_PER_EM as i16).into(),
+                    ASCENDER.into(),
+                )),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let colr = Colr {
+        base_glyph_list: Some(BaseGlyphList::new(base_glyphs.len() as u32, base_glyphs)).into(),
+        layer_list: Some(LayerList::new(
+            builder.layers.len() as u32,
+            builder.layers.clone(),
+        ))
+        .into(),
+        clip_list: Some(ClipList::new(1, clips.len() as u32, clips)).into(),
+        ..Default::default()
+    };
+
+    // Alpha is applied per paint, so the palette only holds opaque colors
+    let color_records = builder
+        .palette
+        .iter()
+        .map(|color| ColorRecord::new(color.blue, color.green, color.red, 255))
+        .collect::<Vec<_>>();
+
+    let cpal = Cpal::new(
+        color_records.len() as u16,
+        1,
+        color_records.len() as u16,
+        Some(color_records),
+        vec![0],
+    );
+
+    ColorTables {
+        outlines: builder.outlines,
+        tables: vec![
+            compile_table(&colr, target_name),
+            compile_table(&cpal, target_name),
+        ],
+    }
+}
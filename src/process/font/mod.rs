@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use write_fonts::{
+    dump_table,
+    read::TopLevelTable,
+    tables::{
+        cmap::Cmap,
+        glyf::{GlyfLocaBuilder, Glyph, SimpleGlyph},
+        gsub::{Gsub, Ligature, LigatureSet, LigatureSubstFormat1, SubstitutionLookup},
+        head::{Flags, Head, MacStyle},
+        hhea::Hhea,
+        hmtx::{Hmtx, LongMetric},
+        layout::{
+            CoverageFormat1, CoverageTable, Feature, FeatureList, FeatureRecord, LangSys, Lookup,
+            LookupFlag, LookupList, Script, ScriptList, ScriptRecord,
+        },
+        maxp::Maxp,
+        name::{Name, NameRecord},
+        os2::{Os2, SelectionFlags},
+        post::Post,
+    },
+    types::{Fixed, GlyphId, GlyphId16, LongDateTime, NameId, Tag},
+    validate::Validate,
+    FontBuilder, FontWrite, OtRound,
+};
+
+use crate::process::{encode::EncodeTarget, metadata::parse_codepoint, EmojiEncoded};
+
+pub mod cbdt;
+pub mod colr;
+pub mod svg;
+
+pub const UNITS_PER_EM: u16 = 1024;
+pub const ASCENDER: i16 = 880;
+pub const DESCENDER: i16 = -144;
+
+// Component glyphs and the ligature glyph they form
+type LigatureEntry = (Vec<GlyphId16>, GlyphId16);
+
+#[derive(Clone, Debug)]
+pub enum FontFormat {
+    Colrv1,
+    Cbdt {
+        sizes: Vec<u32>,
+        encoding: EncodeTarget,
+    },
+    Svg,
+}
+
+impl FontFormat {
+    pub fn to_suffix(&self) -> &'static str {
+        match self {
+            FontFormat::Colrv1 => "colrv1",
+            FontFormat::Cbdt { .. } => "cbdt",
+            FontFormat::Svg => "svg",
+        }
+    }
+}
+
+// Tables and extra outline glyphs produced by a color format
+pub struct ColorTables {
+    pub outlines: Vec<SimpleGlyph>,
+    pub tables: Vec<(Tag, Vec<u8>)>,
+}
+
+// Glyph order shared by all font formats:
+// .notdef, then one glyph per codepoint, then one ligature glyph per multi-codepoint emoji
+pub struct GlyphOrder {
+    pub names: Vec<String>,
+    pub advances: Vec<u16>,
+    pub emojis: Vec<(GlyphId16, usize)>,
+    cmap: Vec<(char, GlyphId)>,
+    ligatures: Vec<(Vec<GlyphId16>, GlyphId16)>,
+    max_context: u16,
+}
+
+fn is_zero_width(codepoint: u32) -> bool {
+    matches!(codepoint, 0x200D | 0xFE0E | 0xFE0F | 0xE0020..=0xE007F)
+}
+
+fn glyph_name(sequence: &[u32]) -> String {
+    let components = sequence
+        .iter()
+        .map(|codepoint| format!("{:04X}", codepoint))
+        .collect::<Vec<_>>();
+
+    format!("u{}", components.join("_"))
+}
+
+impl GlyphOrder {
+    pub fn new(emojis: &[EmojiEncoded], target_name: &str) -> Self {
+        let mut sequences: Vec<Vec<u32>> = Vec::new();
+        let mut sequence_owners: HashMap<Vec<u32>, usize> = HashMap::new();
+
+        for (index, emoji) in emojis.iter().enumerate() {
+            let sequence = match &emoji.emoji.codepoint {
+                Some(codepoint) => parse_codepoint(codepoint)
+                    .iter()
+                    .map(|codepoint| *codepoint as u32)
+                    .collect::<Vec<_>>(),
+                None => panic!(
+                    "Target '{}' builds a font, but emoji '{}' does not have a codepoint",
+                    target_name, emoji.emoji.name
+                ),
+            };
+
+            for codepoint in &sequence {
+                if char::from_u32(*codepoint).is_none() {
+                    panic!(
+                        "Emoji '{}' has an invalid codepoint 'U+{:X}' for target '{}'",
+                        emoji.emoji.name, codepoint, target_name
+                    );
+                }
+            }
+
+            if let Some(owner) = sequence_owners.get(&sequence) {
+                panic!(
+                    "Emojis '{}' and '{}' have the same codepoint in font target '{}'",
+                    emojis[*owner].emoji.name, emoji.emoji.name, target_name
+                );
+            }
+
+            sequence_owners.insert(sequence.clone(), index);
+            sequences.push(sequence);
+        }
+
+        let mut order = Self {
+            names: vec![".notdef".to_string()],
+            advances: vec![UNITS_PER_EM],
+            emojis: Vec::new(),
+            cmap: Vec::new(),
+            ligatures: Vec::new(),
+            max_context: 1,
+        };
+
+        // Every codepoint used anywhere gets a cmap entry
+        let mut codepoints = sequences.iter().flatten().copied().collect::<Vec<_>>();
+        codepoints.sort();
+        codepoints.dedup();
+
+        let mut codepoint_glyphs: HashMap<u32, GlyphId16> = HashMap::new();
+        for codepoint in codepoints {
+            let glyph = order.push_glyph(glyph_name(&[codepoint]), is_zero_width(codepoint));
+
+            order
+                .cmap
+                .push((char::from_u32(codepoint).unwrap(), glyph.into()));
+            codepoint_glyphs.insert(codepoint, glyph);
+
+            if let Some(index) = sequence_owners.get(&vec![codepoint]) {
+                order.emojis.push((glyph, *index));
+            }
+        }
+
+        // Sequences are reached through ligatures of their components
+        for (index, sequence) in sequences.iter().enumerate() {
+            if sequence.len() < 2 {
+                continue;
+            }
+
+            let glyph = order.push_glyph(glyph_name(sequence), false);
+            let components = sequence
+                .iter()
+                .map(|codepoint| codepoint_glyphs[codepoint])
+                .collect::<Vec<_>>();
+
+            order.max_context = order.max_context.max(sequence.len() as u16);
+            order.ligatures.push((components, glyph));
+            order.emojis.push((glyph, index));
+        }
+
+        order
+    }
+
+    fn push_glyph(&mut self, name: String, zero_width: bool) -> GlyphId16 {
+        let glyph = GlyphId16::new(self.names.len() as u16);
+
+        self.names.push(name);
+        self.advances
+            .push(if zero_width { 0 } else { UNITS_PER_EM });
+
+        glyph
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    fn gsub(&self) -> Gsub {
+        // Group by the first component, longest sequences first so they take precedence
+        let mut sets: Vec<(GlyphId16, Vec<LigatureEntry>)> = Vec::new();
+        for (components, glyph) in &self.ligatures {
+            match sets.iter_mut().find(|(first, _)| *first == components[0]) {
+                Some((_, ligatures)) => ligatures.push((components.clone(), *glyph)),
+                None => sets.push((components[0], vec![(components.clone(), *glyph)])),
+            }
+        }
+
+        sets.sort_by_key(|(first, _)| *first);
+        for (_, ligatures) in &mut sets {
+            ligatures.sort_by_key(|(components, _)| std::cmp::Reverse(components.len()));
+        }
+
+        let coverage = CoverageTable::Format1(CoverageFormat1::new(
+            sets.iter().map(|(first, _)| *first).collect(),
+        ));
+
+        let ligature_sets = sets
+            .into_iter()
+            .map(|(_, ligatures)| {
+                LigatureSet::new(
+                    ligatures
+                        .into_iter()
+                        .map(|(components, glyph)| Ligature::new(glyph, components[1..].to_vec()))
+                        .collect(),
+                )
+            })
+            .collect();
+
+        let lookup = SubstitutionLookup::Ligature(Lookup::new(
+            LookupFlag::empty(),
+            vec![LigatureSubstFormat1::new(coverage, ligature_sets)],
+        ));
+
+        Gsub::new(
+            ScriptList::new(vec![ScriptRecord::new(
+                Tag::new(b"DFLT"),
+                Script::new(Some(LangSys::new(vec![0])), vec![]),
+            )]),
+            FeatureList::new(vec![FeatureRecord::new(
+                Tag::new(b"ccmp"),
+                Feature::new(None, vec![0]),
+            )]),
+            LookupList::new(vec![lookup]),
+        )
+    }
+}
+
+fn name_table(family: &str) -> Name {
+    let postscript_name = family
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect::<String>();
+
+    let strings = [
+        (NameId::FAMILY_NAME, family.to_string()),
+        (NameId::SUBFAMILY_NAME, "Regular".to_string()),
+        (
+            NameId::UNIQUE_ID,
+            format!("{};{}", env!("CARGO_PKG_VERSION"), postscript_name),
+        ),
+        (NameId::FULL_NAME, family.to_string()),
+        (
+            NameId::VERSION_STRING,
+            format!("Version {}", env!("CARGO_PKG_VERSION")),
+        ),
+        (NameId::POSTSCRIPT_NAME, postscript_name),
+    ];
+
+    Name::new(
+        strings
+            .into_iter()
+            .map(|(name_id, string)| NameRecord::new(3, 1, 0x409, name_id, string.into()))
+            .collect(),
+    )
+}
+
+pub fn build_font(
+    emojis: &[EmojiEncoded],
+    format: &FontFormat,
+    family: &str,
+    target_name: &str,
+) -> Vec<u8> {
+    let order = GlyphOrder::new(emojis, target_name);
+
+    let color = match format {
+        FontFormat::Colrv1 => colr::build(emojis, &order, target_name),
+        FontFormat::Cbdt { sizes, .. } => cbdt::build(emojis, &order, sizes, target_name),
+        FontFormat::Svg => svg::build(emojis, &order, target_name),
+    };
+
+    let mut names = order.names.clone();
+    let mut advances = order.advances.clone();
+    for index in 0..color.outlines.len() {
+        names.push(format!("layer{}", index));
+        advances.push(UNITS_PER_EM);
+    }
+
+    let num_glyphs = names.len();
+    if num_glyphs > u16::MAX as usize {
+        panic!(
+            "Font target '{}' needs {} glyphs, but a font can only contain {}",
+            target_name,
+            num_glyphs,
+            u16::MAX
+        );
+    }
+
+    // Outlines
+    let mut glyf_builder = GlyfLocaBuilder::new();
+    let mut left_side_bearings: Vec<i16> = vec![0; order.len()];
+    let mut bbox: Option<(i16, i16, i16, i16)> = None;
+    let mut max_points = 0;
+    let mut max_contours = 0;
+
+    for _ in 0..order.len() {
+        glyf_builder.add_glyph(&Glyph::Empty).unwrap();
+    }
+
+    for outline in &color.outlines {
+        if let Err(err) = glyf_builder.add_glyph(outline) {
+            panic!(
+                "Failed to compile glyph outline for target '{}': {}",
+                target_name, err
+            );
+        }
+
+        let glyph_bbox = outline.bbox;
+        left_side_bearings.push(glyph_bbox.x_min);
+
+        bbox = Some(match bbox {
+            Some((x_min, y_min, x_max, y_max)) => (
+                x_min.min(glyph_bbox.x_min),
+                y_min.min(glyph_bbox.y_min),
+                x_max.max(glyph_bbox.x_max),
+                y_max.max(glyph_bbox.y_max),
+            ),
+            None => (
+                glyph_bbox.x_min,
+                glyph_bbox.y_min,
+                glyph_bbox.x_max,
+                glyph_bbox.y_max,
+            ),
+        });
+
+        let points: usize = outline.contours.iter().map(|contour| contour.len()).sum();
+        max_points = max_points.max(points as u16);
+        max_contours = max_contours.max(outline.contours.len() as u16);
+    }
+
+    let (glyf, loca, loca_format) = glyf_builder.build();
+    let (x_min, y_min, x_max, y_max) =
+        bbox.unwrap_or((0, DESCENDER, UNITS_PER_EM as i16, ASCENDER));
+
+    let head = Head {
+        font_revision: Fixed::from_f64(1.0),
+        flags: Flags::BASELINE_AT_Y_0 | Flags::LSB_AT_X_0 | Flags::FORCE_INTEGER_PPEM,
+        units_per_em: UNITS_PER_EM,
+        created: LongDateTime::new(0),
+        modified: LongDateTime::new(0),
+        x_min,
+        y_min,
+        x_max,
+        y_max,
+        mac_style: MacStyle::empty(),
+        lowest_rec_ppem: 8,
+        index_to_loc_format: loca_format as i16,
+        ..Default::default()
+    };
+
+    let hhea = Hhea {
+        ascender: ASCENDER.into(),
+        descender: DESCENDER.into(),
+        line_gap: 0.into(),
+        advance_width_max: UNITS_PER_EM.into(),
+        min_left_side_bearing: left_side_bearings.iter().copied().min().unwrap_or(0).into(),
+        min_right_side_bearing: 0.into(),
+        x_max_extent: x_max.into(),
+        caret_slope_rise: 1,
+        caret_slope_run: 0,
+        caret_offset: 0,
+        number_of_h_metrics: num_glyphs as u16,
+    };
+
+    let hmtx = Hmtx::new(
+        advances
+            .iter()
+            .zip(left_side_bearings.iter())
+            .map(|(advance, side_bearing)| LongMetric::new(*advance, *side_bearing))
+            .collect(),
+        vec![],
+    );
+
+    let maxp = Maxp {
+        num_glyphs: num_glyphs as u16,
+        max_points: Some(max_points),
+        max_contours: Some(max_contours),
+        max_composite_points: Some(0),
+        max_composite_contours: Some(0),
+        max_zones: Some(1),
+        max_twilight_points: Some(0),
+        max_storage: Some(0),
+        max_function_defs: Some(0),
+        max_instruction_defs: Some(0),
+        max_stack_elements: Some(0),
+        max_size_of_instructions: Some(0),
+        max_component_elements: Some(0),
+        max_component_depth: Some(0),
+    };
+
+    let first_char = order.cmap.iter().map(|(c, _)| *c as u32).min().unwrap_or(0);
+    let last_char = order.cmap.iter().map(|(c, _)| *c as u32).max().unwrap_or(0);
+
+    let os2 = Os2 {
+        x_avg_char_width: UNITS_PER_EM as i16,
+        us_weight_class: 400,
+        us_width_class: 5,
+        fs_selection: SelectionFlags::REGULAR | SelectionFlags::USE_TYPO_METRICS,
+        us_first_char_index: first_char.min(0xFFFF) as u16,
+        us_last_char_index: last_char.min(0xFFFF) as u16,
+        s_typo_ascender: ASCENDER,
+        s_typo_descender: DESCENDER,
+        s_typo_line_gap: 0,
+        us_win_ascent: ASCENDER as u16,
+        us_win_descent: (-DESCENDER) as u16,
+        ul_code_page_range_1: Some(1),
+        ul_code_page_range_2: Some(0),
+        sx_height: Some(0),
+        s_cap_height: Some(0),
+        us_default_char: Some(0),
+        us_break_char: Some(0x20),
+        us_max_context: Some(order.max_context),
+        ..Default::default()
+    };
+
+    let cmap = match Cmap::from_mappings(order.cmap.iter().copied()) {
+        Ok(cmap) => cmap,
+        Err(err) => panic!("Failed to build cmap for target '{}': {}", target_name, err),
+    };
+
+    let post = Post::new_v2(names.iter().map(|name| name.as_str()));
+
+    let mut tables = vec![
+        compile_table(&head, target_name),
+        compile_table(&hhea, target_name),
+        compile_table(&maxp, target_name),
+        compile_table(&os2, target_name),
+        compile_table(&hmtx, target_name),
+        compile_table(&cmap, target_name),
+        compile_table(&loca, target_name),
+        compile_table(&glyf, target_name),
+        compile_table(&name_table(family), target_name),
+        compile_table(&post, target_name),
+    ];
+
+    if !order.ligatures.is_empty() {
+        tables.push(compile_table(&order.gsub(), target_name));
+    }
+
+    tables.extend(color.tables);
+
+    let mut builder = FontBuilder::new();
+    for (tag, data) in tables {
+        builder.add_raw(tag, data);
+    }
+
+    builder.build()
+}
+
+pub fn compile_table<T: FontWrite + Validate + TopLevelTable>(
+    table: &T,
+    target_name: &str,
+) -> (Tag, Vec<u8>) {
+    match dump_table(table) {
+        Ok(data) => (T::TAG, data),
+        Err(err) => panic!(
+            "Failed to compile font table '{}' for target '{}': {}",
+            T::TAG,
+            target_name,
+            err
+        ),
+    }
+}
+
+pub fn to_fword(value: f64) -> i16 {
+    value.ot_round()
+}
@@ -0,0 +1,86 @@
+use resvg::usvg::{Tree, TreeParsing};
+use write_fonts::types::Tag;
+
+use super::{ColorTables, GlyphOrder, ASCENDER, UNITS_PER_EM};
+use crate::process::EmojiEncoded;
+
+const HEADER_SIZE: u32 = 10;
+const DOCUMENT_RECORD_SIZE: u32 = 12;
+
+// Wraps the emoji SVG so it covers the em square above the baseline,
+// OT-SVG documents use font units with y pointing down from the baseline
+fn glyph_document(svg: &str, glyph: u16, name: &str) -> String {
+    let tree = match Tree::from_str(svg, &Default::default()) {
+        Ok(tree) => tree,
+        Err(err) => panic!("Error parsing SVG of emoji '{}' for font: {}", name, err),
+    };
+
+    let svg = match svg.find("<svg") {
+        Some(start) => &svg[start..],
+        None => panic!("SVG of emoji '{}' does not have a root element", name),
+    };
+
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><g id="glyph{}" transform="translate(0,{})"><svg width="{}" height="{}" viewBox="0 0 {} {}">{}</svg></g></svg>"#,
+        glyph,
+        -ASCENDER,
+        UNITS_PER_EM,
+        UNITS_PER_EM,
+        tree.size.width(),
+        tree.size.height(),
+        svg
+    )
+}
+
+pub fn build(emojis: &[EmojiEncoded], order: &GlyphOrder, target_name: &str) -> ColorTables {
+    let mut glyphs = order.emojis.clone();
+    glyphs.sort_by_key(|(glyph, _)| *glyph);
+
+    let documents = glyphs
+        .iter()
+        .map(|(glyph, index)| {
+            let emoji = &emojis[*index].emoji;
+            let document =
+                glyph_document(&emoji.svg.as_ref().unwrap().0, glyph.to_u16(), &emoji.name);
+
+            (*glyph, document)
+        })
+        .collect::<Vec<_>>();
+
+    let records_size = 2 + DOCUMENT_RECORD_SIZE * documents.len() as u32;
+    let documents_size: usize = documents.iter().map(|(_, document)| document.len()).sum();
+
+    if records_size as usize + documents_size > u32::MAX as usize {
+        panic!(
+            "SVG documents of font target '{}' are too large for a font",
+            target_name
+        );
+    }
+
+    let mut table = Vec::new();
+    table.extend_from_slice(&0u16.to_be_bytes()); // version
+    table.extend_from_slice(&HEADER_SIZE.to_be_bytes()); // svgDocumentListOffset
+    table.extend_from_slice(&0u32.to_be_bytes()); // reserved
+
+    // Document list, offsets are relative to its start
+    table.extend_from_slice(&(documents.len() as u16).to_be_bytes());
+
+    let mut offset = records_size;
+    for (glyph, document) in &documents {
+        table.extend_from_slice(&glyph.to_u16().to_be_bytes());
+        table.extend_from_slice(&glyph.to_u16().to_be_bytes());
+        table.extend_from_slice(&offset.to_be_bytes());
+        table.extend_from_slice(&(document.len() as u32).to_be_bytes());
+
+        offset += document.len() as u32;
+    }
+
+    for (_, document) in documents {
+        table.extend_from_slice(document.as_bytes());
+    }
+
+    ColorTables {
+        outlines: Vec::new(),
+        tables: vec![(Tag::new(b"SVG "), table)],
+    }
+}
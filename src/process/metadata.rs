@@ -9,9 +9,22 @@ struct Group {
     emojis: Vec<Emoji>,
 }
 
+#[derive(Serialize)]
+struct Source {
+    src: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoder: Option<String>,
+}
+
 #[derive(Serialize)]
 struct Emoji {
     src: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<Source>,
     base: Option<Vec<u64>>,
     alternates: Vec<Vec<u64>>,
     shortcodes: Vec<String>,
@@ -21,7 +34,7 @@ struct Emoji {
     animated: bool,
 }
 
-fn parse_codepoint(codepoint: &Vec<String>) -> Vec<u64> {
+pub fn parse_codepoint(codepoint: &Vec<String>) -> Vec<u64> {
     codepoint
         .iter()
         .map(|codepoint| {
@@ -85,15 +98,34 @@ pub fn generate_metadata(emojis: &Vec<EmojiEncoded>) -> String {
             None => Vec::with_capacity(0),
         };
 
+        // Only listed when there is more than one file to choose from,
+        // or to record which encoder an auto format picked
+        let sources =
+            if emoji.files.len() > 1 || emoji.files.iter().any(|file| file.encoder.is_some()) {
+                emoji
+                    .files
+                    .iter()
+                    .map(|file| Source {
+                        src: file.filename.clone(),
+                        format: file.format.map(|format| format.to_string()),
+                        size: file.size,
+                        encoder: file.encoder.clone(),
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+
         let emoji = Emoji {
-            src: emoji.filename.clone(),
+            src: emoji.files.first().map(|file| file.filename.clone()),
+            sources,
             base: codepoint,
             alternates,
             category: emoji.emoji.category.clone(),
             shortcodes,
             description: emoji.emoji.description.clone(),
             emoticons: Vec::with_capacity(0),
-            animated: false,
+            animated: emoji.files.iter().any(|file| file.animated),
         };
 
         groups.get_mut(&group).unwrap().push(emoji);
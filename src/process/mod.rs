@@ -2,26 +2,65 @@ use rayon::prelude::*;
 use std::{fs, thread};
 
 pub mod encode;
-use encode::encode_raster;
+use encode::{encode_frames, encode_raster, EncodeTarget};
 
-mod rasterize;
-use rasterize::rasterise_svg;
+pub mod rasterize;
+use rasterize::{rasterise_svg, RenderOptions};
+
+pub mod resample;
+
+pub mod effects;
+use effects::{apply_effects, Effect};
 
 pub mod cache;
 
+mod auto;
+use auto::pick_smallest;
+
+pub mod font;
+use font::{build_font, FontFormat};
+
 mod metadata;
 use metadata::generate_metadata;
 
 mod package;
 use package::Package;
 
-use crate::load::manifest::{Emoji, FilenameFormat, OutputFormat};
+use crate::load::manifest::{Emoji, FilenameFormat, OutputFormat, SizeNaming};
 use crate::Pack;
 
+pub struct EncodedFile {
+    pub filename: String,
+    pub format: Option<&'static str>,
+    pub size: Option<u32>,
+    pub animated: bool,
+    pub encoder: Option<String>,
+    data: Option<Vec<u8>>,
+}
+
 pub struct EmojiEncoded {
-    pub filename: Option<String>,
     pub emoji: Emoji,
-    raster: Option<Vec<u8>>,
+    pub files: Vec<EncodedFile>,
+    strikes: Vec<Vec<u8>>,
+}
+
+// With several sizes, each one gets a subdirectory or a suffix relative to the first size
+fn sized_filename(filename: &str, size: u32, sizes: &[u32], naming: &SizeNaming) -> String {
+    if sizes.len() < 2 {
+        return filename.to_string();
+    }
+
+    match naming {
+        SizeNaming::Directory => format!("{}/{}", size, filename),
+        SizeNaming::Suffix => {
+            if size == sizes[0] {
+                return filename.to_string();
+            }
+
+            let scale = (size as f64 / sizes[0] as f64 * 100.0).round() / 100.0;
+            format!("{}@{}x", filename, scale)
+        }
+    }
 }
 
 impl Pack {
@@ -65,8 +104,8 @@ impl Pack {
                 })
                 .map(|emoji| EmojiEncoded {
                     emoji: emoji.clone(),
-                    raster: None,
-                    filename: None,
+                    files: Vec::new(),
+                    strikes: Vec::new(),
                 })
                 .collect::<Vec<_>>();
 
@@ -83,31 +122,64 @@ impl Pack {
 
                 let svg = &emoji.emoji.svg.as_ref().unwrap().0;
 
-                let encoded = match &target.output_format {
-                    OutputFormat::Raster { format, size } => {
-                        match self.cache.try_get(svg, &format, *size) {
-                            Some(encoded) => Some(encoded),
-                            None => {
-                                let raster = rasterise_svg(svg, *size);
-                                let encoded = encode_raster(&raster, &format);
-
-                                if !dry {
-                                    self.cache.save(
-                                        &svg,
-                                        &format,
-                                        *size,
-                                        &encoded,
-                                    );
-                                };
-
-                                Some(encoded)
-                            }
+                let frames = &emoji.emoji.frames;
+                let animated = |format: &EncodeTarget| format.is_animated() && frames.len() > 1;
+
+                let encode_cached = |format: &EncodeTarget, size: u32, render: &RenderOptions, effects: &[Effect]| {
+                    // Animations depend on every frame and its duration
+                    let key = if animated(format) {
+                        frames
+                            .iter()
+                            .map(|(frame, duration)| format!("{}-{}", frame.0, duration))
+                            .collect::<Vec<_>>()
+                            .join("-")
+                    } else {
+                        svg.clone()
+                    };
+
+                    match self.cache.try_get(&key, format, size, render, effects) {
+                        Some(encoded) => encoded,
+                        None => {
+                            let encoded = if animated(format) {
+                                let frames = frames
+                                    .iter()
+                                    .map(|(frame, duration)| {
+                                        let raster = rasterise_svg(&frame.0, size, render);
+                                        (apply_effects(raster, effects), *duration)
+                                    })
+                                    .collect::<Vec<_>>();
+
+                                encode_frames(&frames, format)
+                            } else {
+                                let raster = rasterise_svg(svg, size, render);
+                                let raster = apply_effects(raster, effects);
+                                encode_raster(&raster, format)
+                            };
+
+                            if !dry {
+                                self.cache.save(&key, format, size, render, effects, &encoded);
+                            };
+
+                            encoded
                         }
-                    },
-                    OutputFormat::Svg => None,
-                    OutputFormat::None => None,
+                    }
                 };
 
+                // Bitmap fonts embed one image per strike, always filling the em square
+                for output_format in &target.output_formats {
+                    if let OutputFormat::Font { format: FontFormat::Cbdt { sizes, encoding }, .. } = output_format {
+                        emoji.strikes = sizes
+                            .iter()
+                            .map(|size| encode_cached(encoding, *size, &RenderOptions::default(), &[]))
+                            .collect();
+                    }
+                }
+
+                // Font glyphs are not separate files
+                if target.output_formats.iter().all(|output_format| matches!(output_format, OutputFormat::Font { .. })) {
+                    return;
+                }
+
                 let filename = match &target.output_structure.filenames {
                     FilenameFormat::Codepoint => match emoji.emoji.to_codepoint_filename(target.output_structure.flat) {
                         Some(filename) => filename,
@@ -123,19 +195,102 @@ impl Pack {
                     },
                 };
 
-                let filename = match &target.output_format {
-                    OutputFormat::Svg => format!("{}.svg", filename),
-                    OutputFormat::Raster { format, size: _ } => {
-                        let extension = format.to_extension();
-                        format!("{}.{}", filename, extension)
-                    },
-                    OutputFormat::None => filename,
-                };
-
-                emoji.raster = encoded;
-                emoji.filename = Some(filename);
+                emoji.files = target
+                    .output_formats
+                    .iter()
+                    .flat_map(|output_format| match output_format {
+                        OutputFormat::Svg => vec![EncodedFile {
+                            filename: format!("{}.svg", filename),
+                            format: Some("svg"),
+                            size: None,
+                            animated: false,
+                            encoder: None,
+                            data: Some(svg.as_bytes().to_vec()),
+                        }],
+                        OutputFormat::Raster { format, sizes } => sizes
+                            .iter()
+                            .map(|size| EncodedFile {
+                                filename: format!(
+                                    "{}.{}",
+                                    sized_filename(&filename, *size, sizes, &target.output_structure.sizes),
+                                    format.to_extension()
+                                ),
+                                format: Some(format.to_extension()),
+                                size: Some(*size),
+                                animated: animated(format),
+                                encoder: None,
+                                data: Some(encode_cached(format, *size, &target.render, &target.effects)),
+                            })
+                            .collect(),
+                        OutputFormat::Auto { candidates, min_psnr, sizes } => sizes
+                            .iter()
+                            .map(|size| {
+                                let encoded = candidates
+                                    .iter()
+                                    .map(|(_, format)| encode_cached(format, *size, &target.render, &target.effects))
+                                    .collect::<Vec<_>>();
+
+                                // Only needed to measure the quality of lossy candidates
+                                let reference = min_psnr.map(|_| apply_effects(rasterise_svg(svg, *size, &target.render), &target.effects));
+
+                                let choice = pick_smallest(candidates, &encoded, reference.as_ref(), *min_psnr);
+                                let (encoder, format) = &candidates[choice];
+
+                                EncodedFile {
+                                    filename: format!(
+                                        "{}.{}",
+                                        sized_filename(&filename, *size, sizes, &target.output_structure.sizes),
+                                        format.to_extension()
+                                    ),
+                                    format: Some(format.to_extension()),
+                                    size: Some(*size),
+                                    animated: false,
+                                    encoder: Some(encoder.clone()),
+                                    data: encoded.into_iter().nth(choice),
+                                }
+                            })
+                            .collect(),
+                        OutputFormat::None => vec![EncodedFile {
+                            filename: filename.clone(),
+                            format: None,
+                            size: None,
+                            animated: false,
+                            encoder: None,
+                            data: None,
+                        }],
+                        OutputFormat::Font { .. } => Vec::new(),
+                    })
+                    .collect();
             });
 
+            let font_count = target
+                .output_formats
+                .iter()
+                .filter(|output_format| matches!(output_format, OutputFormat::Font { .. }))
+                .count();
+
+            let mut fonts = Vec::new();
+            for output_format in &target.output_formats {
+                if let OutputFormat::Font { format, family } = output_format {
+                    self.logger
+                        .build(&format!("Building font for target '{}'", target.name));
+
+                    let filename = match target.name.rsplit('/').next() {
+                        Some(filename) => filename.to_string(),
+                        None => "font".to_string(),
+                    };
+
+                    // Several fonts are told apart by their format
+                    let filename = if font_count > 1 {
+                        format!("{}-{}.ttf", filename, format.to_suffix())
+                    } else {
+                        format!("{}.ttf", filename)
+                    };
+
+                    fonts.push((filename, build_font(&emojis, format, family, &target.name)));
+                }
+            }
+
             // Save on a separate thread
             // To continue encoding while saving
             let path = self.output_path.join(&target.name);
@@ -160,23 +315,18 @@ impl Pack {
 
                 // Write emojis
                 for emoji in &emojis {
-                    match &target.output_format {
-                        OutputFormat::None => {}
-                        OutputFormat::Svg => {
-                            package.add_file(
-                                &emoji.emoji.svg.as_ref().unwrap().0.as_bytes().to_vec(),
-                                emoji.filename.as_ref().unwrap(),
-                            );
-                        }
-                        OutputFormat::Raster { format: _, size: _ } => {
-                            package.add_file(
-                                emoji.raster.as_ref().unwrap(),
-                                emoji.filename.as_ref().unwrap(),
-                            );
+                    for file in &emoji.files {
+                        if let Some(data) = &file.data {
+                            package.add_file(data, &file.filename);
                         }
                     }
                 }
 
+                // Write fonts
+                for (filename, font) in fonts {
+                    package.add_file(&font, &filename);
+                }
+
                 // Write metadata
                 let metadata = generate_metadata(&emojis);
                 package.add_file(&metadata.as_bytes().to_vec(), "metadata.json");
@@ -216,3 +366,53 @@ impl Pack {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_sizes_keep_the_filename() {
+        assert_eq!(
+            sized_filename("smile", 64, &[64], &SizeNaming::Directory),
+            "smile"
+        );
+        assert_eq!(
+            sized_filename("smile", 64, &[64], &SizeNaming::Suffix),
+            "smile"
+        );
+    }
+
+    #[test]
+    fn directories_are_named_after_the_size() {
+        let sizes = [32, 64];
+        assert_eq!(
+            sized_filename("smile", 32, &sizes, &SizeNaming::Directory),
+            "32/smile"
+        );
+        assert_eq!(
+            sized_filename("smile", 64, &sizes, &SizeNaming::Directory),
+            "64/smile"
+        );
+    }
+
+    #[test]
+    fn suffixes_are_relative_to_the_first_size() {
+        let sizes = [64, 128, 96, 32, 100];
+        let suffixed = sizes
+            .iter()
+            .map(|size| sized_filename("smile", *size, &sizes, &SizeNaming::Suffix))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            suffixed,
+            [
+                "smile",
+                "smile@2x",
+                "smile@1.5x",
+                "smile@0.5x",
+                "smile@1.56x"
+            ]
+        );
+    }
+}
@@ -1,29 +1,117 @@
 use resvg::{
     render,
-    tiny_skia::{Pixmap, Transform},
+    tiny_skia::{Color, Pixmap, Transform},
     usvg::{Tree, TreeParsing},
     FitTo,
 };
 
 use image::RgbaImage;
 
-pub fn rasterise_svg(svg: &String, size: u32) -> RgbaImage {
+use crate::process::resample::{downscale, DownscaleFilter};
+
+#[derive(Clone, Debug, Default)]
+pub enum AspectMode {
+    #[default]
+    Stretch,
+    Preserve,
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub aspect: AspectMode,
+    pub padding: f64, // fraction of the image on each side
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub background: Option<[u8; 4]>,
+    pub supersample: u32,
+    pub filter: DownscaleFilter,
+    pub snap: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            aspect: AspectMode::default(),
+            padding: 0.0,
+            width: None,
+            height: None,
+            background: None,
+            supersample: 1,
+            filter: DownscaleFilter::default(),
+            snap: false,
+        }
+    }
+}
+
+pub fn rasterise_svg(svg: &String, size: u32, options: &RenderOptions) -> RgbaImage {
     let tree = Tree::from_str(&svg, &Default::default()).unwrap();
 
-    let mut pixmap = Pixmap::new(size, size).unwrap();
+    let width = options.width.unwrap_or(size);
+    let height = options.height.unwrap_or(size);
+
+    let supersample = options.supersample;
+    let mut pixmap = Pixmap::new(width * supersample, height * supersample).unwrap();
+
+    if let Some([r, g, b, a]) = options.background {
+        pixmap.fill(Color::from_rgba8(r, g, b, a));
+    }
+
+    // Area left for the emoji after padding
+    let padding_x = width as f32 * options.padding as f32;
+    let padding_y = height as f32 * options.padding as f32;
+    let inner_width = width as f32 - padding_x * 2.0;
+    let inner_height = height as f32 - padding_y * 2.0;
+
+    let mut emoji_width = inner_width;
+    let mut emoji_height = inner_height;
+
+    if let AspectMode::Preserve = options.aspect {
+        let scale =
+            (inner_width / tree.size.width() as f32).min(inner_height / tree.size.height() as f32);
+
+        emoji_width = tree.size.width() as f32 * scale;
+        emoji_height = tree.size.height() as f32 * scale;
+    }
+
+    // Center the emoji when it doesn't fill the area
+    let mut offset_x = padding_x + (inner_width - emoji_width) / 2.0;
+    let mut offset_y = padding_y + (inner_height - emoji_height) / 2.0;
+
+    // Keep the edges of the emoji on whole output pixels
+    if options.snap {
+        emoji_width = emoji_width.round().max(1.0);
+        emoji_height = emoji_height.round().max(1.0);
+        offset_x = offset_x.round();
+        offset_y = offset_y.round();
+    }
+
+    let scale_x = emoji_width / tree.size.width() as f32;
+    let scale_y = emoji_height / tree.size.height() as f32;
+
     render(
         &tree,
         FitTo::Original,
-        Transform::default().pre_scale(
-            size as f32 / tree.size.width() as f32,
-            size as f32 / tree.size.height() as f32,
-        ),
+        Transform::from_scale(supersample as f32, supersample as f32)
+            .pre_translate(offset_x, offset_y)
+            .pre_scale(scale_x, scale_y),
         pixmap.as_mut(),
     )
     .unwrap();
 
-    let data = pixmap.data();
-    let rgba_image = RgbaImage::from_raw(size, size, data.to_vec()).unwrap();
+    let data = if supersample > 1 {
+        downscale(
+            pixmap.data(),
+            pixmap.width(),
+            pixmap.height(),
+            width,
+            height,
+            &options.filter,
+        )
+    } else {
+        pixmap.data().to_vec()
+    };
+
+    let rgba_image = RgbaImage::from_raw(width, height, data).unwrap();
 
     rgba_image
 }
@@ -0,0 +1,138 @@
+use std::f32::consts::PI;
+
+#[derive(Clone, Debug, Default)]
+pub enum DownscaleFilter {
+    Box,
+    Mitchell,
+    #[default]
+    Lanczos,
+}
+
+impl DownscaleFilter {
+    fn radius(&self) -> f32 {
+        match self {
+            DownscaleFilter::Box => 0.5,
+            DownscaleFilter::Mitchell => 2.0,
+            DownscaleFilter::Lanczos => 3.0,
+        }
+    }
+
+    fn weight(&self, x: f32) -> f32 {
+        let x = x.abs();
+
+        match self {
+            DownscaleFilter::Box => {
+                if x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            // Mitchell-Netravali with B = C = 1/3
+            DownscaleFilter::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else if x < 2.0 {
+                    ((-b - 6.0 * c) * x.powi(3)
+                        + (6.0 * b + 30.0 * c) * x.powi(2)
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    0.0
+                }
+            }
+            DownscaleFilter::Lanczos => {
+                if x == 0.0 {
+                    1.0
+                } else if x < 3.0 {
+                    let x = x * PI;
+                    3.0 * x.sin() * (x / 3.0).sin() / (x * x)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    // Normalized source weights for every destination pixel along one axis
+    fn weights(&self, src: u32, dst: u32) -> Vec<(usize, Vec<f32>)> {
+        let scale = src as f32 / dst as f32;
+        let support = self.radius() * scale;
+
+        (0..dst)
+            .map(|index| {
+                let center = (index as f32 + 0.5) * scale;
+                let start = (center - support).floor().max(0.0) as usize;
+                let end = ((center + support).ceil() as usize).min(src as usize);
+
+                let mut weights = (start..end)
+                    .map(|src_index| self.weight((src_index as f32 + 0.5 - center) / scale))
+                    .collect::<Vec<_>>();
+
+                let total: f32 = weights.iter().sum();
+                if total != 0.0 {
+                    weights.iter_mut().for_each(|weight| *weight /= total);
+                }
+
+                (start, weights)
+            })
+            .collect()
+    }
+}
+
+// Separable resampling of premultiplied RGBA pixels
+pub fn downscale(
+    data: &[u8],
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+    filter: &DownscaleFilter,
+) -> Vec<u8> {
+    let horizontal = filter.weights(src_width, dst_width);
+    let vertical = filter.weights(src_height, dst_height);
+
+    let mut rows = vec![0.0f32; dst_width as usize * src_height as usize * 4];
+    for y in 0..src_height as usize {
+        for (x, (start, weights)) in horizontal.iter().enumerate() {
+            for channel in 0..4 {
+                let mut value = 0.0;
+                for (offset, weight) in weights.iter().enumerate() {
+                    let index = (y * src_width as usize + start + offset) * 4 + channel;
+                    value += data[index] as f32 * weight;
+                }
+
+                rows[(y * dst_width as usize + x) * 4 + channel] = value;
+            }
+        }
+    }
+
+    let mut output = vec![0u8; dst_width as usize * dst_height as usize * 4];
+    for (y, (start, weights)) in vertical.iter().enumerate() {
+        for x in 0..dst_width as usize {
+            let mut pixel = [0.0f32; 4];
+            for (channel, value) in pixel.iter_mut().enumerate() {
+                for (offset, weight) in weights.iter().enumerate() {
+                    let index = ((start + offset) * dst_width as usize + x) * 4 + channel;
+                    *value += rows[index] * weight;
+                }
+            }
+
+            // Ringing filters can overshoot, color can't exceed alpha when premultiplied
+            let alpha = pixel[3].round().clamp(0.0, 255.0);
+            for (channel, value) in pixel.iter().enumerate() {
+                let value = value.round().clamp(0.0, 255.0);
+                output[(y * dst_width as usize + x) * 4 + channel] =
+                    if channel < 3 { value.min(alpha) } else { alpha } as u8;
+            }
+        }
+    }
+
+    output
+}